@@ -10,6 +10,7 @@ fn main() {
         .with_points_file("assets/cities.geojson".into())
         .with_simplification_epsilons([1.50, 0.80, 0.40, 0.20, 0.10, 0.05, 0.01])
         .with_output_dir("assets/generated".into())
+        .with_archive_file("assets/generated/tiles.gaia".into())
         .run()
         .unwrap();
 }
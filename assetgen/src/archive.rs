@@ -0,0 +1,386 @@
+//! A single file containing the assets for every tile.
+//!
+//! An archive is laid out as follows, with all integers little-endian:
+//!
+//! * A header: the bytes `GAIAARCH`, followed by the format version as a `u32`.
+//! * The contents of each asset, one after the other.
+//! * An index, with an entry for each asset: the level (`u8`), x (`u32`) and y (`u32`) of its
+//!   tile, its kind (`u8`), and the offset and length of its contents in the file (`u64` each).
+//! * A footer: the offset of the index and the number of entries in it (`u64` each), followed by
+//!   the bytes `GAIAARCH` again.
+//!
+//! Putting the index at the end lets archives be written in a single pass, and lets a reader find
+//! any asset after reading only the footer and index.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use gaia_quadtree::Tile;
+
+const MAGIC: &[u8; 8] = b"GAIAARCH";
+const VERSION: u32 = 1;
+
+const HEADER_SIZE: u64 = 12;
+const FOOTER_SIZE: u64 = 24;
+const INDEX_ENTRY_SIZE: u64 = 26;
+
+/// The assets that each tile has.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AssetKind {
+    /// Satellite imagery, as a JPEG.
+    Color,
+    /// Elevation data, as a grid of `u16`s.
+    Elevation,
+    /// A JSON-encoded `TileMetadata`.
+    Metadata,
+    /// Satellite imagery, block-compressed with its mip chain. See the `texture` module. Only
+    /// present if assets were generated with color compression.
+    CompressedColor,
+}
+
+impl AssetKind {
+    /// Every kind of asset.
+    pub fn all() -> [AssetKind; 4] {
+        [
+            AssetKind::Color,
+            AssetKind::Elevation,
+            AssetKind::Metadata,
+            AssetKind::CompressedColor,
+        ]
+    }
+
+    /// The file extension used for this kind of asset when it is stored as a separate file.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            AssetKind::Color => "jpg",
+            AssetKind::Elevation => "gray",
+            AssetKind::Metadata => "json",
+            AssetKind::CompressedColor => "bc",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            AssetKind::Color => 0,
+            AssetKind::Elevation => 1,
+            AssetKind::Metadata => 2,
+            AssetKind::CompressedColor => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<AssetKind> {
+        match byte {
+            0 => Some(AssetKind::Color),
+            1 => Some(AssetKind::Elevation),
+            2 => Some(AssetKind::Metadata),
+            3 => Some(AssetKind::CompressedColor),
+            _ => None,
+        }
+    }
+}
+
+/// Where an asset's contents are within an archive.
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    offset: u64,
+    length: u64,
+}
+
+/// Writes an archive, one asset at a time.
+///
+/// The archive is incomplete until `finish` is called.
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    position: u64,
+    index: Vec<(Tile, AssetKind, Location)>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<ArchiveWriter<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(VERSION)?;
+
+        Ok(ArchiveWriter {
+            writer,
+            position: HEADER_SIZE,
+            index: Vec::new(),
+        })
+    }
+
+    /// Add an asset to the archive. The tile's offset is ignored.
+    pub fn add(&mut self, tile: &Tile, kind: AssetKind, contents: &[u8]) -> io::Result<()> {
+        self.writer.write_all(contents)?;
+
+        let location = Location {
+            offset: self.position,
+            length: contents.len() as u64,
+        };
+
+        self.index.push((tile.to_origin(), kind, location));
+        self.position += location.length;
+
+        Ok(())
+    }
+
+    /// Write out the index, completing the archive, and get back the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        for &(ref tile, kind, location) in &self.index {
+            self.writer.write_u8(tile.level)?;
+            self.writer.write_u32::<LittleEndian>(tile.x)?;
+            self.writer.write_u32::<LittleEndian>(tile.y)?;
+            self.writer.write_u8(kind.to_byte())?;
+            self.writer.write_u64::<LittleEndian>(location.offset)?;
+            self.writer.write_u64::<LittleEndian>(location.length)?;
+        }
+
+        self.writer.write_u64::<LittleEndian>(self.position)?;
+        self.writer.write_u64::<LittleEndian>(self.index.len() as u64)?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// Reads assets from an archive, without reading any other assets.
+pub struct ArchiveReader<R: Read + Seek> {
+    reader: R,
+    index: HashMap<(Tile, AssetKind), Location>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Open an archive, reading its index.
+    pub fn new(mut reader: R) -> io::Result<ArchiveReader<R>> {
+        let mut magic = [0; 8];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut magic)?;
+        let version = reader.read_u32::<LittleEndian>()?;
+
+        if &magic != MAGIC {
+            return Err(invalid_data("not a Gaia tile archive"));
+        }
+
+        if version != VERSION {
+            return Err(invalid_data("unsupported tile archive version"));
+        }
+
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        if file_size < HEADER_SIZE + FOOTER_SIZE {
+            return Err(invalid_data("tile archive is truncated"));
+        }
+
+        reader.seek(SeekFrom::Start(file_size - FOOTER_SIZE))?;
+        let index_offset = reader.read_u64::<LittleEndian>()?;
+        let num_entries = reader.read_u64::<LittleEndian>()?;
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid_data("tile archive is incomplete"));
+        }
+
+        let index_end = num_entries
+            .checked_mul(INDEX_ENTRY_SIZE)
+            .and_then(|index_size| index_offset.checked_add(index_size));
+        if index_offset < HEADER_SIZE || index_end != Some(file_size - FOOTER_SIZE) {
+            return Err(invalid_data("tile archive index is truncated"));
+        }
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+
+        let mut index = HashMap::new();
+        for _ in 0..num_entries {
+            let level = reader.read_u8()?;
+            let x = reader.read_u32::<LittleEndian>()?;
+            let y = reader.read_u32::<LittleEndian>()?;
+            let kind = AssetKind::from_byte(reader.read_u8()?)
+                .ok_or_else(|| invalid_data("unknown asset kind in tile archive"))?;
+            let offset = reader.read_u64::<LittleEndian>()?;
+            let length = reader.read_u64::<LittleEndian>()?;
+
+            match offset.checked_add(length) {
+                Some(end) if offset >= HEADER_SIZE && end <= index_offset => {}
+                _ => return Err(invalid_data("tile archive index is corrupt")),
+            }
+
+            let tile = Tile::new_at_origin(level, x, y);
+            index.insert((tile, kind), Location { offset, length });
+        }
+
+        Ok(ArchiveReader { reader, index })
+    }
+
+    /// Whether the archive has the asset of kind `kind` for `tile`.
+    pub fn contains(&self, tile: &Tile, kind: AssetKind) -> bool {
+        self.index.contains_key(&(tile.to_origin(), kind))
+    }
+
+    /// Read the asset of kind `kind` for `tile`, if the archive has it. The tile's offset is
+    /// ignored.
+    pub fn read(&mut self, tile: &Tile, kind: AssetKind) -> io::Result<Option<Vec<u8>>> {
+        let location = match self.index.get(&(tile.to_origin(), kind)) {
+            Some(&location) => location,
+            None => return Ok(None),
+        };
+
+        let mut contents = vec![0; location.length as usize];
+        self.reader.seek(SeekFrom::Start(location.offset))?;
+        self.reader.read_exact(&mut contents)?;
+
+        Ok(Some(contents))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::u64;
+
+    use super::*;
+
+    fn archive() -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer
+            .add(&Tile::new_at_origin(0, 1, 0), AssetKind::Color, b"color")
+            .unwrap();
+        writer
+            .add(&Tile::new_at_origin(3, 5, 2), AssetKind::Metadata, b"{}")
+            .unwrap();
+        writer
+            .add(&Tile::new_at_origin(3, 5, 2), AssetKind::Elevation, b"")
+            .unwrap();
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn open(data: Vec<u8>) -> io::Result<ArchiveReader<Cursor<Vec<u8>>>> {
+        ArchiveReader::new(Cursor::new(data))
+    }
+
+    fn assert_invalid(data: Vec<u8>, message: &str) {
+        match open(data) {
+            Ok(_) => panic!("expected an error: {}", message),
+            Err(error) => {
+                assert_eq!(io::ErrorKind::InvalidData, error.kind());
+                assert_eq!(message, error.to_string());
+            }
+        }
+    }
+
+    /// Overwrite the little-endian `u64` at `position` in `data`.
+    fn set_u64(data: &mut [u8], position: usize, value: u64) {
+        let mut bytes = Vec::new();
+        bytes.write_u64::<LittleEndian>(value).unwrap();
+        data[position..position + 8].copy_from_slice(&bytes);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut reader = open(archive()).unwrap();
+        let tile = Tile::new_at_origin(3, 5, 2);
+
+        assert!(reader.contains(&tile, AssetKind::Metadata));
+        assert!(!reader.contains(&tile, AssetKind::Color));
+
+        assert_eq!(
+            Some(b"color".to_vec()),
+            reader
+                .read(&Tile::new_at_origin(0, 1, 0), AssetKind::Color)
+                .unwrap()
+        );
+        assert_eq!(
+            Some(b"{}".to_vec()),
+            reader.read(&tile, AssetKind::Metadata).unwrap()
+        );
+        assert_eq!(
+            Some(vec![]),
+            reader.read(&tile, AssetKind::Elevation).unwrap()
+        );
+        assert_eq!(None, reader.read(&tile, AssetKind::Color).unwrap());
+
+        // The tile's offset is ignored.
+        let mut offset_tile = tile.clone();
+        offset_tile.offset = -3;
+        assert_eq!(
+            Some(b"{}".to_vec()),
+            reader.read(&offset_tile, AssetKind::Metadata).unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_archive() {
+        let writer = ArchiveWriter::new(Cursor::new(Vec::new())).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        let reader = open(data).unwrap();
+        assert!(!reader.contains(&Tile::new_at_origin(0, 0, 0), AssetKind::Color));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut data = archive();
+        data[0] = b'X';
+
+        assert_invalid(data, "not a Gaia tile archive");
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut data = archive();
+        data[8] = VERSION as u8 + 1;
+
+        assert_invalid(data, "unsupported tile archive version");
+    }
+
+    #[test]
+    fn missing_footer() {
+        let mut data = archive();
+        let len = data.len();
+        data.truncate(len - 1);
+
+        assert_invalid(data, "tile archive is incomplete");
+    }
+
+    #[test]
+    fn truncated_index() {
+        // Remove the last index entry, but leave the footer.
+        let mut data = archive();
+        let footer = data.len() - FOOTER_SIZE as usize;
+        data.drain(footer - INDEX_ENTRY_SIZE as usize..footer);
+
+        assert_invalid(data, "tile archive index is truncated");
+    }
+
+    #[test]
+    fn too_many_index_entries() {
+        let mut data = archive();
+        let num_entries_position = data.len() - 16;
+        set_u64(&mut data, num_entries_position, u64::MAX);
+
+        assert_invalid(data, "tile archive index is truncated");
+    }
+
+    #[test]
+    fn overflowing_index_entry() {
+        // Make the first entry's length wrap around when added to its offset.
+        let mut data = archive();
+        let index_offset = data.len() - FOOTER_SIZE as usize - 3 * INDEX_ENTRY_SIZE as usize;
+        set_u64(&mut data, index_offset + 18, u64::MAX);
+
+        assert_invalid(data, "tile archive index is corrupt");
+    }
+
+    #[test]
+    fn index_entry_in_header() {
+        let mut data = archive();
+        let index_offset = data.len() - FOOTER_SIZE as usize - 3 * INDEX_ENTRY_SIZE as usize;
+        set_u64(&mut data, index_offset + 10, 4);
+        set_u64(&mut data, index_offset + 18, 1);
+
+        assert_invalid(data, "tile archive index is corrupt");
+    }
+}
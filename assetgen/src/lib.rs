@@ -3,28 +3,36 @@ extern crate error_chain;
 #[macro_use]
 extern crate serde_derive;
 
+extern crate byteorder;
+extern crate gaia_quadtree;
 extern crate geo;
 extern crate geojson;
+extern crate image;
 extern crate serde;
 extern crate serde_json;
 extern crate tempdir;
 
-use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 
+use gaia_quadtree::{cover, QuadTree, Tile, TileRange};
+use gaia_quadtree::projection::{lon_lat_to_world, normalize_lat, normalize_lon};
 use geojson::GeoJson;
 use geojson::conversion::TryInto;
 use geo::boundingbox::BoundingBox;
 use geo::simplifyvw::SimplifyVW;
 use tempdir::TempDir;
 
+pub mod archive;
 mod errors;
 mod imagemagick;
+pub mod texture;
 
+use archive::{ArchiveWriter, AssetKind};
 use errors::*;
 use imagemagick::Convert;
+use texture::ColorCompression;
 
 pub const ELEVATION_OFFSET: u16 = 500;
 
@@ -109,6 +117,8 @@ pub struct PrepareAssetsTask {
     points_file: PathBuf,
     simplification_epsilons: [f32; MAX_LEVEL as usize + 1],
     output_dir: PathBuf,
+    archive_file: Option<PathBuf>,
+    color_compression: Option<ColorCompression>,
 }
 
 impl PrepareAssetsTask {
@@ -120,6 +130,8 @@ impl PrepareAssetsTask {
             points_file: "".into(),
             simplification_epsilons: [0.0; MAX_LEVEL as usize + 1],
             output_dir: "".into(),
+            archive_file: None,
+            color_compression: None,
         }
     }
 
@@ -155,6 +167,29 @@ impl PrepareAssetsTask {
         PrepareAssetsTask { output_dir, ..self }
     }
 
+    /// Also pack the assets for every tile into a single archive file, in the format described in
+    /// the `archive` module.
+    pub fn with_archive_file(self, archive_file: PathBuf) -> PrepareAssetsTask {
+        PrepareAssetsTask {
+            archive_file: Some(archive_file),
+            ..self
+        }
+    }
+
+    /// Also store the imagery for every tile block-compressed, along with its mip chain, in the
+    /// format described in the `texture` module. These files are named `{level}_{x}_{y}.bc`.
+    /// Files left by an earlier run are regenerated if they were compressed differently, and
+    /// removed if this isn't set.
+    ///
+    /// Gaia decompresses this imagery before uploading it, because gfx can't upload compressed
+    /// textures one layer at a time, so it doesn't save any GPU memory.
+    pub fn with_color_compression(self, color_compression: ColorCompression) -> PrepareAssetsTask {
+        PrepareAssetsTask {
+            color_compression: Some(color_compression),
+            ..self
+        }
+    }
+
     pub fn with_simplification_epsilons(
         self,
         simplification_epsilons: [f32; MAX_LEVEL as usize + 1],
@@ -187,6 +222,15 @@ impl PrepareAssetsTask {
 
         self.create_polygon_data()?;
 
+        match self.color_compression {
+            Some(color_compression) => self.create_compressed_imagery(color_compression)?,
+            None => self.remove_compressed_imagery()?,
+        }
+
+        if let Some(ref archive_file) = self.archive_file {
+            self.create_archive(archive_file)?;
+        }
+
         Ok(())
     }
 
@@ -371,41 +415,27 @@ impl PrepareAssetsTask {
             return Ok(());
         }
 
-        let tiles_across_width = 2u32.pow(1 + level as u32);
-        let tiles_across_height = 2u32.pow(level as u32);
-
-        for x in 0..tiles_across_width {
-            for y in 0..tiles_across_height {
-                let crop = format!("{}_{}_{}.pgm", level, x, y);
-
-                let top_y = y * 2 + 1;
-                let bottom_y = y * 2;
-                let left_x = x * 2;
-                let right_x = x * 2 + 1;
-
-                let top_left = format!("{}_{}_{}.pgm", level + 1, left_x, top_y);
-                let top_right = format!("{}_{}_{}.pgm", level + 1, right_x, top_y);
-                let bottom_left = format!("{}_{}_{}.pgm", level + 1, left_x, bottom_y);
-                let bottom_right = format!("{}_{}_{}.pgm", level + 1, right_x, bottom_y);
-
-                Convert::new()
-                    .group(|convert| {
-                        convert
-                            .input(&temp_crop_dir.join(top_left))
-                            .input(&temp_crop_dir.join(top_right))
-                            .append_horizontally()
-                    })
-                    .group(|convert| {
-                        convert
-                            .input(&temp_crop_dir.join(bottom_left))
-                            .input(&temp_crop_dir.join(bottom_right))
-                            .append_horizontally()
-                    })
-                    .append_vertically()
-                    .resize("50%")
-                    .output(&temp_crop_dir.join(crop))
-                    .run()?;
-            }
+        for tile in TileRange::level(level) {
+            let crop = tile_file_name(&tile, "pgm");
+            let children = children_file_names(&tile, "pgm");
+
+            Convert::new()
+                .group(|convert| {
+                    convert
+                        .input(&temp_crop_dir.join(&children.top_left))
+                        .input(&temp_crop_dir.join(&children.top_right))
+                        .append_horizontally()
+                })
+                .group(|convert| {
+                    convert
+                        .input(&temp_crop_dir.join(&children.bottom_left))
+                        .input(&temp_crop_dir.join(&children.bottom_right))
+                        .append_horizontally()
+                })
+                .append_vertically()
+                .resize("50%")
+                .output(&temp_crop_dir.join(crop))
+                .run()?;
         }
 
         Ok(())
@@ -493,7 +523,7 @@ impl PrepareAssetsTask {
                     max_elevation,
                 };
 
-                let metadata_path = format!("{}_{}_{}-first-pass.json", level, x, y);
+                let metadata_path = first_pass_file_name(&Tile::new_at_origin(level, x, y));
                 let metadata_file = File::create(self.tiles_dir().join(&metadata_path))
                     .chain_err(|| "Error creating first-pass metadata file")?;
 
@@ -517,41 +547,27 @@ impl PrepareAssetsTask {
             return Ok(());
         }
 
-        let tiles_across_width = 2u32.pow(1 + level as u32);
-        let tiles_across_height = 2u32.pow(level as u32);
-
-        for x in 0..tiles_across_width {
-            for y in 0..tiles_across_height {
-                let tile = format!("{}_{}_{}.jpg", level, x, y);
-
-                let top_y = y * 2 + 1;
-                let bottom_y = y * 2;
-                let left_x = x * 2;
-                let right_x = x * 2 + 1;
-
-                let top_left = format!("{}_{}_{}.jpg", level + 1, left_x, top_y);
-                let top_right = format!("{}_{}_{}.jpg", level + 1, right_x, top_y);
-                let bottom_left = format!("{}_{}_{}.jpg", level + 1, left_x, bottom_y);
-                let bottom_right = format!("{}_{}_{}.jpg", level + 1, right_x, bottom_y);
-
-                Convert::new()
-                    .group(|convert| {
-                        convert
-                            .input(&self.tiles_dir().join(top_left))
-                            .input(&self.tiles_dir().join(top_right))
-                            .append_horizontally()
-                    })
-                    .group(|convert| {
-                        convert
-                            .input(&self.tiles_dir().join(bottom_left))
-                            .input(&self.tiles_dir().join(bottom_right))
-                            .append_horizontally()
-                    })
-                    .append_vertically()
-                    .resize("50%")
-                    .output(&self.tiles_dir().join(tile))
-                    .run()?;
-            }
+        for tile in TileRange::level(level) {
+            let tile_file = tile_file_name(&tile, "jpg");
+            let children = children_file_names(&tile, "jpg");
+
+            Convert::new()
+                .group(|convert| {
+                    convert
+                        .input(&self.tiles_dir().join(&children.top_left))
+                        .input(&self.tiles_dir().join(&children.top_right))
+                        .append_horizontally()
+                })
+                .group(|convert| {
+                    convert
+                        .input(&self.tiles_dir().join(&children.bottom_left))
+                        .input(&self.tiles_dir().join(&children.bottom_right))
+                        .append_horizontally()
+                })
+                .append_vertically()
+                .resize("50%")
+                .output(&self.tiles_dir().join(tile_file))
+                .run()?;
         }
 
         Ok(())
@@ -664,73 +680,61 @@ impl PrepareAssetsTask {
         points: &[geo::Point<f32>],
         level: u8,
     ) -> Result<()> {
-        let tiles_across_width = 2u32.pow(1 + level as u32);
-        let tiles_across_height = 2u32.pow(level as u32);
+        let mut tile_polygons = QuadTree::new();
+        let mut tile_points = QuadTree::new();
 
-        let mut tile_polygons = BTreeMap::new();
-        let mut tile_points = BTreeMap::new();
-
-        for x in 0..tiles_across_width {
-            for y in 0..tiles_across_height {
-                tile_polygons.insert((x, y), Vec::new());
-                tile_points.insert((x, y), Vec::new());
-            }
+        for tile in TileRange::level(level) {
+            tile_polygons.insert(&tile, Vec::new());
+            tile_points.insert(&tile, Vec::new());
         }
 
         for (polygon_index, polygon) in polygons.iter().enumerate() {
-            let bounding_box = polygon.exterior.bbox().unwrap();
-            let x_min = tiles_across_width as f32 * self.map_x_coord(bounding_box.xmin);
-            let x_max = tiles_across_width as f32 * self.map_x_coord(bounding_box.xmax);
-            let y_min = tiles_across_height as f32 * self.map_y_coord(bounding_box.ymin);
-            let y_max = tiles_across_height as f32 * self.map_y_coord(bounding_box.ymax);
-
-            for x in x_min.floor() as u32..x_max.ceil() as u32 {
-                for y in y_min.floor() as u32..y_max.ceil() as u32 {
-                    tile_polygons
-                        .get_mut(&(x, y))
-                        .unwrap()
-                        .push(polygon_index as u64);
-                }
+            let exterior = line_string_to_world(&polygon.exterior);
+            let interiors: Vec<_> = polygon.interiors.iter().map(line_string_to_world).collect();
+
+            // Polygons touching the antimeridian can produce tiles in the next copy of the world.
+            // These are looked up without regard to their offset, so they wrap around.
+            for tile in cover::polygon_cover(level, &exterior, &interiors) {
+                tile_polygons
+                    .get_mut(&tile)
+                    .unwrap()
+                    .push(polygon_index as u64);
             }
         }
 
         for (point_index, point) in points.iter().enumerate() {
-            let x = (tiles_across_width as f32 * self.map_x_coord(point.x())).floor() as u32;
-            let y = (tiles_across_height as f32 * self.map_y_coord(point.y())).floor() as u32;
+            let position = lon_lat_to_world([point.x(), point.y()], 0);
 
             tile_points
-                .get_mut(&(x, y))
+                .get_mut(&Tile::enclosing_point(level, position))
                 .unwrap()
                 .push(point_index as u64);
         }
 
-        for x in 0..tiles_across_width {
-            for y in 0..tiles_across_height {
-                let polygon_indices = tile_polygons.get(&(x, y)).unwrap();
-                let point_indices = tile_points.get(&(x, y)).unwrap();
-
-                let first_pass_path = format!("{}_{}_{}-first-pass.json", level, x, y);
-                let first_pass_file = File::open(self.tiles_dir().join(first_pass_path))
-                    .chain_err(|| "Error opening first-pass metadata file")?;
-
-                let first_pass_metadata: FirstPassTileMetadata =
-                    serde_json::from_reader(first_pass_file)
-                        .chain_err(|| "Error parsing first-pass metadata")?;
-
-                let tile_metadata = TileMetadata {
-                    min_elevation: first_pass_metadata.min_elevation,
-                    max_elevation: first_pass_metadata.max_elevation,
-                    polygons: polygon_indices.clone(),
-                    points: point_indices.clone(),
-                };
+        for tile in TileRange::level(level) {
+            let polygon_indices = tile_polygons.get(&tile).unwrap();
+            let point_indices = tile_points.get(&tile).unwrap();
 
-                let metadata_path = format!("{}_{}_{}.json", level, x, y);
-                let metadata_file = File::create(self.tiles_dir().join(metadata_path))
-                    .chain_err(|| "Error creating metadata file")?;
+            let first_pass_path = self.tiles_dir().join(first_pass_file_name(&tile));
+            let first_pass_file = File::open(first_pass_path)
+                .chain_err(|| "Error opening first-pass metadata file")?;
 
-                serde_json::to_writer(metadata_file, &tile_metadata)
-                    .chain_err(|| "Error writing out metadata file")?;
-            }
+            let first_pass_metadata: FirstPassTileMetadata =
+                serde_json::from_reader(first_pass_file)
+                    .chain_err(|| "Error parsing first-pass metadata")?;
+
+            let tile_metadata = TileMetadata {
+                min_elevation: first_pass_metadata.min_elevation,
+                max_elevation: first_pass_metadata.max_elevation,
+                polygons: polygon_indices.clone(),
+                points: point_indices.clone(),
+            };
+
+            let metadata_file = File::create(self.tiles_dir().join(tile_file_name(&tile, "json")))
+                .chain_err(|| "Error creating metadata file")?;
+
+            serde_json::to_writer(metadata_file, &tile_metadata)
+                .chain_err(|| "Error writing out metadata file")?;
         }
 
         Ok(())
@@ -749,14 +753,8 @@ impl PrepareAssetsTask {
             .map(|(polygon, properties)| {
                 let bounding_box = polygon.bbox().unwrap();
                 let bounding_box = [
-                    (
-                        self.map_x_coord(bounding_box.xmin),
-                        self.map_x_coord(bounding_box.xmax),
-                    ),
-                    (
-                        self.map_y_coord(bounding_box.ymin),
-                        self.map_y_coord(bounding_box.ymax),
-                    ),
+                    (normalize_lon(bounding_box.xmin), normalize_lon(bounding_box.xmax)),
+                    (normalize_lat(bounding_box.ymin), normalize_lat(bounding_box.ymax)),
                 ];
 
                 let levels = (0..MAX_LEVEL + 1)
@@ -766,13 +764,14 @@ impl PrepareAssetsTask {
                         let interior_points =
                             simplified_polygon.interiors.into_iter().flat_map(|line| {
                                 line.into_iter().map(|point| {
-                                    (self.map_x_coord(point.x()), self.map_y_coord(point.y()))
+                                    (normalize_lon(point.x()), normalize_lat(point.y()))
                                 })
                             });
 
-                        let exterior_points = simplified_polygon.exterior.into_iter().map(
-                            |point| (self.map_x_coord(point.x()), self.map_y_coord(point.y())),
-                        );
+                        let exterior_points = simplified_polygon
+                            .exterior
+                            .into_iter()
+                            .map(|point| (normalize_lon(point.x()), normalize_lat(point.y())));
 
                         exterior_points.chain(interior_points).collect()
                     })
@@ -790,12 +789,12 @@ impl PrepareAssetsTask {
             .into_iter()
             .zip(point_properties)
             .map(|(point, properties)| {
-                let coordinates = [self.map_x_coord(point.x()), self.map_y_coord(point.y())];
+                let coordinates = [normalize_lon(point.x()), normalize_lat(point.y())];
 
                 let levels = (0..MAX_LEVEL + 1)
                     .map(|level| {
-                        let point_x = self.map_x_coord(point.x());
-                        let point_y = self.map_y_coord(point.y());
+                        let point_x = normalize_lon(point.x());
+                        let point_y = normalize_lat(point.y());
 
                         let tiles_across_width = 2u32.pow(1 + level as u32);
                         let tiles_across_height = 2u32.pow(level as u32);
@@ -848,15 +847,135 @@ impl PrepareAssetsTask {
         Ok(())
     }
 
-    fn map_x_coord(&self, x: f32) -> f32 {
-        (x + 180.0) / 360.0
+    fn create_compressed_imagery(&self, color_compression: ColorCompression) -> Result<()> {
+        for level in 0..MAX_LEVEL + 1 {
+            for tile in TileRange::level(level) {
+                // Files left by an earlier run are kept, unless they were compressed differently.
+                let compressed_path = self.tiles_dir().join(tile_file_name(&tile, "bc"));
+                if compressed_file_format(&compressed_path) == Some(color_compression) {
+                    continue;
+                }
+
+                let image_path = self.tiles_dir().join(tile_file_name(&tile, "jpg"));
+                let rgba = image::open(&image_path)
+                    .chain_err(|| format!("Error reading {}", image_path.display()))?
+                    .to_rgba()
+                    .into_raw();
+
+                let levels = texture::mip_chain(rgba, IMAGERY_TILE_SIZE)
+                    .ok_or_else(|| format!("{} is the wrong size", image_path.display()))?;
+                let compressed = texture::compress(&levels, IMAGERY_TILE_SIZE, color_compression);
+
+                File::create(&compressed_path)
+                    .and_then(|mut file| file.write_all(&compressed))
+                    .chain_err(|| format!("Error writing {}", compressed_path.display()))?;
+            }
+        }
+
+        Ok(())
     }
 
-    fn map_y_coord(&self, y: f32) -> f32 {
-        (y + 90.0) / 180.0
+    /// Remove compressed imagery left by an earlier run, which would otherwise be loaded instead of
+    /// the imagery.
+    fn remove_compressed_imagery(&self) -> Result<()> {
+        for level in 0..MAX_LEVEL + 1 {
+            for tile in TileRange::level(level) {
+                let compressed_path = self.tiles_dir().join(tile_file_name(&tile, "bc"));
+                if compressed_path.exists() {
+                    fs::remove_file(&compressed_path)
+                        .chain_err(|| format!("Error removing {}", compressed_path.display()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_archive(&self, archive_file: &Path) -> Result<()> {
+        let file = File::create(archive_file).chain_err(|| "Error creating archive file")?;
+        let mut archive =
+            ArchiveWriter::new(BufWriter::new(file)).chain_err(|| "Error writing archive file")?;
+
+        for level in 0..MAX_LEVEL + 1 {
+            for tile in TileRange::level(level) {
+                for &kind in &AssetKind::all() {
+                    if kind == AssetKind::CompressedColor && self.color_compression.is_none() {
+                        continue;
+                    }
+
+                    let path = self.tiles_dir().join(tile_file_name(&tile, kind.extension()));
+
+                    let mut contents = Vec::new();
+                    File::open(&path)
+                        .and_then(|mut file| file.read_to_end(&mut contents))
+                        .chain_err(|| format!("Error reading {}", path.display()))?;
+
+                    archive
+                        .add(&tile, kind, &contents)
+                        .chain_err(|| "Error writing archive file")?;
+                }
+            }
+        }
+
+        archive.finish().chain_err(|| "Error writing archive file")?;
+        Ok(())
     }
 
     fn tiles_dir(&self) -> PathBuf {
         self.output_dir.join("tiles")
     }
 }
+
+/// The names of the files for the four children of a tile.
+///
+/// Tiles with a greater y-value are further north, so they go on top when the children are
+/// combined into an image of their parent.
+struct ChildrenFileNames {
+    top_left: String,
+    top_right: String,
+    bottom_left: String,
+    bottom_right: String,
+}
+
+fn line_string_to_world(line_string: &geo::LineString<f32>) -> Vec<[f32; 2]> {
+    line_string
+        .0
+        .iter()
+        .map(|point| lon_lat_to_world([point.x(), point.y()], 0))
+        .collect()
+}
+
+fn tile_file_name(tile: &Tile, extension: &str) -> String {
+    format!("{}.{}", tile_file_stem(tile), extension)
+}
+
+fn first_pass_file_name(tile: &Tile) -> String {
+    format!("{}-first-pass.json", tile_file_stem(tile))
+}
+
+fn tile_file_stem(tile: &Tile) -> String {
+    format!("{}_{}_{}", tile.level, tile.x, tile.y)
+}
+
+/// The format of the compressed imagery at `path`, or `None` if there is no such file or it isn't
+/// valid compressed imagery.
+fn compressed_file_format(path: &Path) -> Option<ColorCompression> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .ok()?;
+
+    texture::split_compressed(&data, IMAGERY_TILE_SIZE).map(|(compression, _)| compression)
+}
+
+fn children_file_names(tile: &Tile, extension: &str) -> ChildrenFileNames {
+    // Children are ordered by increasing y, and then by increasing x.
+    let children = tile.children();
+
+    ChildrenFileNames {
+        bottom_left: tile_file_name(&children[0], extension),
+        bottom_right: tile_file_name(&children[1], extension),
+        top_left: tile_file_name(&children[2], extension),
+        top_right: tile_file_name(&children[3], extension),
+    }
+}
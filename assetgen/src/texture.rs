@@ -0,0 +1,515 @@
+//! Preparing color textures for the GPU, by generating their mip chains and compressing them, and
+//! decompressing them again.
+//!
+//! Compressed color tiles are stored as a single byte identifying the `ColorCompression` used,
+//! followed by each level of the mip chain in turn, largest first. Each level is a grid of 4×4
+//! pixel blocks, row by row from the top, in the BC1 or BC3 format.
+
+use image::{self, ImageBuffer, Rgba};
+
+/// The block compression formats that color tiles can be stored in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorCompression {
+    /// BC1, also known as DXT1. Colors only, at four bits per pixel.
+    Bc1,
+    /// BC3, also known as DXT5. Colors and alpha, at eight bits per pixel.
+    Bc3,
+}
+
+impl ColorCompression {
+    /// The number of bytes used for each 4×4 block of pixels.
+    pub fn block_size(&self) -> usize {
+        match *self {
+            ColorCompression::Bc1 => 8,
+            ColorCompression::Bc3 => 16,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ColorCompression::Bc1 => 1,
+            ColorCompression::Bc3 => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<ColorCompression> {
+        match byte {
+            1 => Some(ColorCompression::Bc1),
+            3 => Some(ColorCompression::Bc3),
+            _ => None,
+        }
+    }
+}
+
+/// The width of each level of the mip chain of a square texture `size` pixels wide, from `size`
+/// down to one. Each level is half as wide as the one before, rounded down.
+pub fn mip_sizes(size: u32) -> Vec<u32> {
+    let mut sizes = vec![size];
+    while *sizes.last().unwrap() > 1 {
+        let next_size = sizes.last().unwrap() / 2;
+        sizes.push(next_size);
+    }
+
+    sizes
+}
+
+/// Generate the mip chain of a square RGBA image that is `size` pixels wide. The first level is
+/// the image itself.
+///
+/// Returns `None` if `rgba` is not `size` pixels wide and tall.
+pub fn mip_chain(rgba: Vec<u8>, size: u32) -> Option<Vec<Vec<u8>>> {
+    if rgba.len() != 4 * size as usize * size as usize {
+        return None;
+    }
+
+    let mut previous: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(size, size, rgba)?;
+    let mut levels = vec![previous.clone().into_raw()];
+
+    for &level_size in &mip_sizes(size)[1..] {
+        let level = image::imageops::resize(
+            &previous,
+            level_size,
+            level_size,
+            image::FilterType::Triangle,
+        );
+
+        levels.push(level.clone().into_raw());
+        previous = level;
+    }
+
+    Some(levels)
+}
+
+/// Compress each level of a mip chain, as generated by `mip_chain`, into the format described in
+/// the module documentation.
+pub fn compress(levels: &[Vec<u8>], size: u32, compression: ColorCompression) -> Vec<u8> {
+    let mut data = vec![compression.to_byte()];
+
+    for (level, level_size) in levels.iter().zip(mip_sizes(size)) {
+        compress_level(level, level_size, compression, &mut data);
+    }
+
+    data
+}
+
+/// Split compressed color data, in the format described in the module documentation, into each
+/// level of its mip chain.
+///
+/// Returns `None` if the data is not a compressed square texture `size` pixels wide.
+pub fn split_compressed(data: &[u8], size: u32) -> Option<(ColorCompression, Vec<&[u8]>)> {
+    let compression = ColorCompression::from_byte(*data.first()?)?;
+
+    let mut levels = Vec::new();
+    let mut rest = &data[1..];
+
+    for level_size in mip_sizes(size) {
+        let blocks_across = (level_size as usize + 3) / 4;
+        let level_length = blocks_across * blocks_across * compression.block_size();
+
+        if rest.len() < level_length {
+            return None;
+        }
+
+        let (level, remainder) = rest.split_at(level_length);
+        levels.push(level);
+        rest = remainder;
+    }
+
+    if rest.is_empty() {
+        Some((compression, levels))
+    } else {
+        None
+    }
+}
+
+/// Decompress each level of a mip chain, as split by `split_compressed`, back into RGBA pixels.
+pub fn decompress(levels: &[&[u8]], size: u32, compression: ColorCompression) -> Vec<Vec<u8>> {
+    levels
+        .iter()
+        .zip(mip_sizes(size))
+        .map(|(level, level_size)| decompress_level(level, level_size, compression))
+        .collect()
+}
+
+fn compress_level(rgba: &[u8], size: u32, compression: ColorCompression, data: &mut Vec<u8>) {
+    let size = size as usize;
+
+    for block_y in 0..(size + 3) / 4 {
+        for block_x in 0..(size + 3) / 4 {
+            // Blocks that go past the edge of the image repeat its last row or column.
+            let mut pixels = [[0; 4]; 16];
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let x = (block_x * 4 + i % 4).min(size - 1);
+                let y = (block_y * 4 + i / 4).min(size - 1);
+                let offset = 4 * (x + y * size);
+
+                pixel.copy_from_slice(&rgba[offset..offset + 4]);
+            }
+
+            if compression == ColorCompression::Bc3 {
+                data.extend_from_slice(&compress_alpha_block(&pixels));
+            }
+
+            data.extend_from_slice(&compress_color_block(&pixels));
+        }
+    }
+}
+
+fn decompress_level(blocks: &[u8], size: u32, compression: ColorCompression) -> Vec<u8> {
+    let size = size as usize;
+    let blocks_across = (size + 3) / 4;
+    let mut rgba = vec![0; 4 * size * size];
+
+    for (i, block) in blocks.chunks(compression.block_size()).enumerate() {
+        let pixels = match compression {
+            ColorCompression::Bc1 => decompress_color_block(block, true),
+            ColorCompression::Bc3 => {
+                let mut pixels = decompress_color_block(&block[8..], false);
+                for (pixel, alpha) in pixels.iter_mut().zip(&decompress_alpha_block(block)) {
+                    pixel[3] = *alpha;
+                }
+
+                pixels
+            }
+        };
+
+        // Pixels of blocks that go past the edge of the image are dropped.
+        for (j, pixel) in pixels.iter().enumerate() {
+            let x = (i % blocks_across) * 4 + j % 4;
+            let y = (i / blocks_across) * 4 + j / 4;
+
+            if x < size && y < size {
+                let offset = 4 * (x + y * size);
+                rgba[offset..offset + 4].copy_from_slice(pixel);
+            }
+        }
+    }
+
+    rgba
+}
+
+/// Compress the colors of a block of pixels, in the BC1 format. The endpoints are the corners of
+/// the block's bounding box in RGB space.
+fn compress_color_block(pixels: &[[u8; 4]; 16]) -> [u8; 8] {
+    let mut min = [255; 3];
+    let mut max = [0; 3];
+    for pixel in pixels {
+        for channel in 0..3 {
+            min[channel] = min[channel].min(pixel[channel]);
+            max[channel] = max[channel].max(pixel[channel]);
+        }
+    }
+
+    let (mut color0, mut color1) = (to_rgb565(max), to_rgb565(min));
+
+    // The first color must be greater than the second, or else the block is decoded with only
+    // three colors.
+    if color0 < color1 {
+        ::std::mem::swap(&mut color0, &mut color1);
+    }
+
+    let mut indices = 0u32;
+    if color0 != color1 {
+        let (a, b) = (from_rgb565(color0), from_rgb565(color1));
+        let palette = [
+            a,
+            b,
+            interpolate(a, b, 2, 1, 3),
+            interpolate(a, b, 1, 2, 3),
+        ];
+
+        for (i, pixel) in pixels.iter().enumerate() {
+            let index = closest(&palette, |color| {
+                (0..3)
+                    .map(|channel| {
+                        let difference = color[channel] as i32 - pixel[channel] as i32;
+                        difference * difference
+                    })
+                    .sum()
+            });
+
+            indices |= (index as u32) << (2 * i);
+        }
+    }
+
+    let mut block = [0; 8];
+    block[0..2].copy_from_slice(&[color0 as u8, (color0 >> 8) as u8]);
+    block[2..4].copy_from_slice(&[color1 as u8, (color1 >> 8) as u8]);
+    for i in 0..4 {
+        block[4 + i] = (indices >> (8 * i)) as u8;
+    }
+
+    block
+}
+
+/// Compress the alphas of a block of pixels, in the format of the first half of a BC3 block.
+fn compress_alpha_block(pixels: &[[u8; 4]; 16]) -> [u8; 8] {
+    let alpha0 = pixels.iter().map(|pixel| pixel[3]).max().unwrap();
+    let alpha1 = pixels.iter().map(|pixel| pixel[3]).min().unwrap();
+
+    let mut indices = 0u64;
+    if alpha0 != alpha1 {
+        // With the first alpha greater than the second, the six alphas after them are evenly
+        // spaced between them.
+        let mut palette = vec![alpha0 as i32, alpha1 as i32];
+        for i in 1..7 {
+            palette.push(((7 - i) * alpha0 as i32 + i * alpha1 as i32) / 7);
+        }
+
+        for (i, pixel) in pixels.iter().enumerate() {
+            let index = closest(&palette, |&alpha| (alpha - pixel[3] as i32).abs());
+            indices |= (index as u64) << (3 * i);
+        }
+    }
+
+    let mut block = [0; 8];
+    block[0] = alpha0;
+    block[1] = alpha1;
+    for i in 0..6 {
+        block[2 + i] = (indices >> (8 * i)) as u8;
+    }
+
+    block
+}
+
+/// Decompress a block of colors in the BC1 format. In a BC1 block whose first color is not greater
+/// than its second, the fourth color is transparent black, but the colors in BC3 blocks are always
+/// opaque.
+fn decompress_color_block(block: &[u8], bc1: bool) -> [[u8; 4]; 16] {
+    let color0 = block[0] as u16 | (block[1] as u16) << 8;
+    let color1 = block[2] as u16 | (block[3] as u16) << 8;
+    let (a, b) = (from_rgb565(color0), from_rgb565(color1));
+
+    let opaque = |color: [u8; 3]| [color[0], color[1], color[2], 255];
+    let palette = if color0 > color1 || !bc1 {
+        [
+            opaque(a),
+            opaque(b),
+            opaque(interpolate(a, b, 2, 1, 3)),
+            opaque(interpolate(a, b, 1, 2, 3)),
+        ]
+    } else {
+        [opaque(a), opaque(b), opaque(interpolate(a, b, 1, 1, 2)), [0; 4]]
+    };
+
+    let indices = (0..4).fold(0u32, |indices, i| indices | (block[4 + i] as u32) << (8 * i));
+
+    let mut pixels = [[0; 4]; 16];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        *pixel = palette[((indices >> (2 * i)) & 3) as usize];
+    }
+
+    pixels
+}
+
+/// Decompress the alphas in the first half of a BC3 block. If the first alpha is not greater than
+/// the second, only four alphas are spaced between them, followed by zero and 255.
+fn decompress_alpha_block(block: &[u8]) -> [u8; 16] {
+    let (alpha0, alpha1) = (block[0] as u32, block[1] as u32);
+
+    let mut palette = vec![alpha0, alpha1];
+    if alpha0 > alpha1 {
+        for i in 1..7 {
+            palette.push(((7 - i) * alpha0 + i * alpha1) / 7);
+        }
+    } else {
+        for i in 1..5 {
+            palette.push(((5 - i) * alpha0 + i * alpha1) / 5);
+        }
+        palette.extend_from_slice(&[0, 255]);
+    }
+
+    let indices = (0..6).fold(0u64, |indices, i| indices | (block[2 + i] as u64) << (8 * i));
+
+    let mut alphas = [0; 16];
+    for (i, alpha) in alphas.iter_mut().enumerate() {
+        *alpha = palette[((indices >> (3 * i)) & 7) as usize] as u8;
+    }
+
+    alphas
+}
+
+/// The index of the item in `palette` for which `distance` is smallest.
+fn closest<T, F: Fn(&T) -> i32>(palette: &[T], distance: F) -> usize {
+    (0..palette.len())
+        .min_by_key(|&index| distance(&palette[index]))
+        .unwrap()
+}
+
+fn to_rgb565(color: [u8; 3]) -> u16 {
+    let r = (color[0] as u16 * 31 + 127) / 255;
+    let g = (color[1] as u16 * 63 + 127) / 255;
+    let b = (color[2] as u16 * 31 + 127) / 255;
+
+    (r << 11) | (g << 5) | b
+}
+
+fn from_rgb565(color: u16) -> [u8; 3] {
+    let r = (color >> 11) & 31;
+    let g = (color >> 5) & 63;
+    let b = color & 31;
+
+    [
+        (r * 255 / 31) as u8,
+        (g * 255 / 63) as u8,
+        (b * 255 / 31) as u8,
+    ]
+}
+
+/// `(a * weight_a + b * weight_b) / total`, for each channel.
+fn interpolate(a: [u8; 3], b: [u8; 3], weight_a: u16, weight_b: u16, total: u16) -> [u8; 3] {
+    let mut result = [0; 3];
+    for channel in 0..3 {
+        let sum = a[channel] as u16 * weight_a + b[channel] as u16 * weight_b;
+        result[channel] = (sum / total) as u8;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square image `size` pixels wide, with every pixel given by `pixel`.
+    fn image<F: Fn(u32, u32) -> [u8; 4]>(size: u32, pixel: F) -> Vec<u8> {
+        let mut rgba = Vec::new();
+        for y in 0..size {
+            for x in 0..size {
+                rgba.extend_from_slice(&pixel(x, y));
+            }
+        }
+
+        rgba
+    }
+
+    /// Compress and then decompress just the first level of an image's mip chain.
+    fn round_trip_level(rgba: Vec<u8>, size: u32, compression: ColorCompression) -> Vec<u8> {
+        let data = compress(&[rgba], size, compression);
+
+        decompress(&[&data[1..]], size, compression).remove(0)
+    }
+
+    #[test]
+    fn mip_sizes_of_imagery_tile() {
+        assert_eq!(vec![675, 337, 168, 84, 42, 21, 10, 5, 2, 1], mip_sizes(675));
+        assert_eq!(vec![1], mip_sizes(1));
+    }
+
+    #[test]
+    fn mip_chain_sizes() {
+        let levels = mip_chain(image(6, |_, _| [10, 20, 30, 255]), 6).unwrap();
+
+        let lengths: Vec<_> = levels.iter().map(Vec::len).collect();
+        assert_eq!(vec![6 * 6 * 4, 3 * 3 * 4, 4], lengths);
+        assert_eq!(vec![10, 20, 30, 255], levels[2]);
+    }
+
+    #[test]
+    fn mip_chain_wrong_size() {
+        assert_eq!(None, mip_chain(image(5, |_, _| [0; 4]), 6));
+        assert_eq!(None, mip_chain(vec![0; 7], 1));
+    }
+
+    #[test]
+    fn round_trip() {
+        let rgba = image(6, |x, y| [(40 * x) as u8, (40 * y) as u8, 128, (x * y) as u8]);
+        let levels = mip_chain(rgba, 6).unwrap();
+
+        for &compression in &[ColorCompression::Bc1, ColorCompression::Bc3] {
+            let data = compress(&levels, 6, compression);
+            let (split_compression, split_levels) = split_compressed(&data, 6).unwrap();
+
+            // Levels 6, 3 and 1 pixels wide take up four, one and one blocks.
+            let lengths: Vec<_> = split_levels.iter().map(|level| level.len()).collect();
+            let block_size = compression.block_size();
+            assert_eq!(compression, split_compression);
+            assert_eq!(vec![4 * block_size, block_size, block_size], lengths);
+            assert_eq!(&data[1..], &split_levels.concat()[..]);
+        }
+    }
+
+    #[test]
+    fn split_rejects_bad_data() {
+        let levels = mip_chain(image(6, |_, _| [0; 4]), 6).unwrap();
+        let data = compress(&levels, 6, ColorCompression::Bc1);
+
+        assert!(split_compressed(&data[..data.len() - 1], 6).is_none());
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(split_compressed(&trailing, 6).is_none());
+
+        let mut unknown_compression = data.clone();
+        unknown_compression[0] = 2;
+        assert!(split_compressed(&unknown_compression, 6).is_none());
+
+        assert!(split_compressed(&data, 8).is_none());
+        assert!(split_compressed(&[], 6).is_none());
+    }
+
+    #[test]
+    fn decompress_mip_chain() {
+        let levels = mip_chain(image(6, |_, _| [255, 0, 255, 255]), 6).unwrap();
+
+        for &compression in &[ColorCompression::Bc1, ColorCompression::Bc3] {
+            let data = compress(&levels, 6, compression);
+            let (_, split_levels) = split_compressed(&data, 6).unwrap();
+
+            assert_eq!(levels, decompress(&split_levels, 6, compression));
+        }
+    }
+
+    #[test]
+    fn solid_color_block() {
+        let rgba = image(4, |_, _| [255, 0, 255, 255]);
+
+        assert_eq!(rgba, round_trip_level(rgba.clone(), 4, ColorCompression::Bc1));
+    }
+
+    #[test]
+    fn solid_color_block_is_quantized() {
+        let rgba = image(4, |_, _| [100, 150, 200, 255]);
+        let decompressed = round_trip_level(rgba, 4, ColorCompression::Bc1);
+
+        for pixel in decompressed.chunks(4) {
+            assert!((pixel[0] as i32 - 100).abs() <= 8);
+            assert!((pixel[1] as i32 - 150).abs() <= 4);
+            assert!((pixel[2] as i32 - 200).abs() <= 8);
+            assert_eq!(255, pixel[3]);
+        }
+    }
+
+    #[test]
+    fn two_color_block() {
+        let pixel = |x, _| if x < 2 { [0, 0, 0, 255] } else { [255, 255, 255, 255] };
+        let rgba = image(4, pixel);
+
+        assert_eq!(rgba, round_trip_level(rgba.clone(), 4, ColorCompression::Bc1));
+    }
+
+    #[test]
+    fn partial_block() {
+        // A 2×2 image is still one 4×4 block, of which only the top left is kept.
+        let rgba = image(2, |x, y| if x == y { [0, 0, 0, 255] } else { [255, 255, 255, 255] });
+
+        assert_eq!(rgba, round_trip_level(rgba.clone(), 2, ColorCompression::Bc1));
+    }
+
+    #[test]
+    fn solid_alpha_block() {
+        let rgba = image(4, |_, _| [255, 0, 0, 77]);
+        assert_eq!(rgba, round_trip_level(rgba.clone(), 4, ColorCompression::Bc3));
+
+        // Both endpoints are the alpha, so every index decodes to it.
+        let data = compress(&[rgba], 4, ColorCompression::Bc3);
+        assert_eq!(&[77, 77], &data[1..3]);
+    }
+
+    #[test]
+    fn two_alpha_block() {
+        let rgba = image(4, |x, _| [0, 0, 0, if x < 2 { 0 } else { 255 }]);
+
+        assert_eq!(rgba, round_trip_level(rgba.clone(), 4, ColorCompression::Bc3));
+    }
+}
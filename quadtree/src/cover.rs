@@ -0,0 +1,364 @@
+//! Computing the tiles that a geometry intersects.
+//!
+//! All coordinates are world coordinates, as described in the `projection` module. Geometries may
+//! extend past the edges of a copy of the world, in which case the returned tiles will have
+//! different offsets.
+//!
+//! Tiles are returned without duplicates, sorted by offset, then `x`, then `y`. Points with
+//! coordinates that aren't finite are ignored.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::{f32, f64};
+
+use {Tile, MAX_LEVEL};
+
+/// The tiles at level `level` that intersect the box from `min` to `max`.
+pub fn bounding_box_cover(level: u8, min: [f32; 2], max: [f32; 2]) -> Vec<Tile> {
+    let grid = Grid::new(level);
+    if !is_finite(min) || !is_finite(max) {
+        return Vec::new();
+    }
+
+    let (min_column, min_row) = grid.cell(min);
+    let (max_column, max_row) = grid.cell_ending_at(max);
+
+    let mut tiles = HashSet::new();
+    for column in min_column..max_column.max(min_column) + 1 {
+        for row in min_row..max_row.max(min_row) + 1 {
+            tiles.insert(grid.tile(column, row));
+        }
+    }
+
+    sorted(tiles)
+}
+
+/// The tiles at level `level` that intersect the line going through each of `points` in order.
+pub fn line_cover(level: u8, points: &[[f32; 2]]) -> Vec<Tile> {
+    let grid = Grid::new(level);
+    let mut tiles = HashSet::new();
+
+    add_line(&grid, &finite_points(points), &mut tiles);
+    sorted(tiles)
+}
+
+/// The tiles at level `level` that intersect a polygon.
+///
+/// `exterior` is the outer ring of the polygon, and `interiors` are the holes within it. Rings may
+/// or may not repeat their first point at the end.
+pub fn polygon_cover(level: u8, exterior: &[[f32; 2]], interiors: &[Vec<[f32; 2]>]) -> Vec<Tile> {
+    let grid = Grid::new(level);
+    let mut tiles = HashSet::new();
+
+    let rings: Vec<Vec<[f32; 2]>> = Some(exterior)
+        .into_iter()
+        .chain(interiors.iter().map(|ring| ring.as_slice()))
+        .map(finite_points)
+        .filter(|ring| !ring.is_empty())
+        .map(|mut ring| {
+            let first = ring[0];
+            ring.push(first);
+            ring
+        })
+        .collect();
+
+    // Tiles that the edges of the polygon pass through.
+    for ring in &rings {
+        add_line(&grid, ring, &mut tiles);
+    }
+
+    // Tiles that the edges of the polygon don't pass through are either entirely inside or outside
+    // of it. Those entirely inside are the ones whose center is inside the polygon, which is found
+    // by scanning across the row of centers for each row of tiles.
+    let all_y = || rings.iter().flat_map(|ring| ring.iter()).map(|point| point[1]);
+    let min_y = all_y().fold(f32::INFINITY, f32::min);
+    let max_y = all_y().fold(f32::NEG_INFINITY, f32::max);
+
+    if min_y > max_y {
+        return sorted(tiles);
+    }
+
+    let (_, min_row) = grid.cell([0.0, min_y]);
+    let (_, max_row) = grid.cell([0.0, max_y]);
+
+    for row in min_row..max_row + 1 {
+        let center_y = (row as f64 + 0.5) * grid.width;
+
+        let mut crossings = Vec::new();
+        for ring in &rings {
+            for edge in ring.windows(2) {
+                let (a, b) = (to_f64(edge[0]), to_f64(edge[1]));
+
+                if (a[1] <= center_y) != (b[1] <= center_y) {
+                    let t = (center_y - a[1]) / (b[1] - a[1]);
+                    crossings.push(a[0] + t * (b[0] - a[0]));
+                }
+            }
+        }
+
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        for span in crossings.chunks(2) {
+            if span.len() < 2 {
+                continue;
+            }
+
+            let first_column = (span[0] / grid.width - 0.5).ceil() as i64;
+            let last_column = (span[1] / grid.width - 0.5).floor() as i64;
+
+            for column in first_column..last_column + 1 {
+                tiles.insert(grid.tile(column, row));
+            }
+        }
+    }
+
+    sorted(tiles)
+}
+
+/// The grid of tiles at a level, addressed by column and row across all copies of the world.
+struct Grid {
+    level: u8,
+    width: f64,
+    rows: i64,
+}
+
+impl Grid {
+    fn new(level: u8) -> Grid {
+        assert!(level <= MAX_LEVEL, "level {} is too deep", level);
+
+        Grid {
+            level,
+            width: 1.0 / 2.0f64.powi(level as i32),
+            rows: Tile::tiles_across_height(level) as i64,
+        }
+    }
+
+    /// The column and row of the tile containing `point`.
+    fn cell(&self, point: [f32; 2]) -> (i64, i64) {
+        let point = to_f64(point);
+        let column = (point[0] / self.width).floor() as i64;
+        let row = (point[1] / self.width).floor() as i64;
+
+        (column, self.clamp_row(row))
+    }
+
+    /// The column and row of the tile containing `point`, treating points on the edge between two
+    /// tiles as part of the lower one.
+    fn cell_ending_at(&self, point: [f32; 2]) -> (i64, i64) {
+        let point = to_f64(point);
+        let column = (point[0] / self.width).ceil() as i64 - 1;
+        let row = (point[1] / self.width).ceil() as i64 - 1;
+
+        (column, self.clamp_row(row))
+    }
+
+    fn clamp_row(&self, row: i64) -> i64 {
+        row.max(0).min(self.rows - 1)
+    }
+
+    fn tile(&self, column: i64, row: i64) -> Tile {
+        Tile::new_at_origin(self.level, 0, 0).offset_by(column, row)
+    }
+}
+
+/// Add the tiles that the line through `points` passes through, using the grid traversal algorithm
+/// of Amanatides and Woo.
+fn add_line(grid: &Grid, points: &[[f32; 2]], tiles: &mut HashSet<Tile>) {
+    if points.len() == 1 {
+        let (column, row) = grid.cell(points[0]);
+        tiles.insert(grid.tile(column, row));
+    }
+
+    for segment in points.windows(2) {
+        let (start, end) = (to_f64(segment[0]), to_f64(segment[1]));
+        let (mut column, mut row) = grid.cell(segment[0]);
+        let (end_column, end_row) = grid.cell(segment[1]);
+
+        let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+        let (step_x, mut t_max_x, t_delta_x) = axis_steps(start[0], dx, column, grid.width);
+        let (step_y, mut t_max_y, t_delta_y) = axis_steps(start[1], dy, row, grid.width);
+
+        let num_steps = (end_column - column).abs() + (end_row - row).abs();
+        tiles.insert(grid.tile(column, row));
+
+        for _ in 0..num_steps {
+            if t_max_x < t_max_y {
+                t_max_x += t_delta_x;
+                column += step_x;
+            } else {
+                t_max_y += t_delta_y;
+                row = grid.clamp_row(row + step_y);
+            }
+
+            tiles.insert(grid.tile(column, row));
+        }
+    }
+}
+
+/// For a line starting at `start` and moving by `delta` along one axis, get the direction it steps
+/// in, how far along the line it first crosses a cell boundary, and how far along the line it moves
+/// between boundaries. Distances along the line are between zero and one.
+fn axis_steps(start: f64, delta: f64, cell: i64, width: f64) -> (i64, f64, f64) {
+    if delta > 0.0 {
+        let boundary = (cell + 1) as f64 * width;
+        (1, (boundary - start) / delta, width / delta)
+    } else if delta < 0.0 {
+        let boundary = cell as f64 * width;
+        (-1, (boundary - start) / delta, -width / delta)
+    } else {
+        (0, f64::INFINITY, f64::INFINITY)
+    }
+}
+
+fn is_finite(point: [f32; 2]) -> bool {
+    point[0].is_finite() && point[1].is_finite()
+}
+
+fn finite_points(points: &[[f32; 2]]) -> Vec<[f32; 2]> {
+    points.iter().cloned().filter(|&point| is_finite(point)).collect()
+}
+
+fn to_f64(point: [f32; 2]) -> [f64; 2] {
+    [point[0] as f64, point[1] as f64]
+}
+
+fn sorted(tiles: HashSet<Tile>) -> Vec<Tile> {
+    let mut tiles: Vec<_> = tiles.into_iter().collect();
+    tiles.sort_by_key(|tile| (tile.offset, tile.x, tile.y));
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles(level: u8, coordinates: &[(u32, u32)]) -> Vec<Tile> {
+        coordinates
+            .iter()
+            .map(|&(x, y)| Tile::new_at_origin(level, x, y))
+            .collect()
+    }
+
+    #[test]
+    fn bounding_box_cover() {
+        assert_eq!(
+            tiles(2, &[(1, 1), (1, 2), (2, 1), (2, 2)]),
+            super::bounding_box_cover(2, [0.3, 0.3], [0.6, 0.6])
+        );
+
+        // Boxes that only touch a tile do not cover it.
+        assert_eq!(
+            tiles(2, &[(1, 1)]),
+            super::bounding_box_cover(2, [0.25, 0.25], [0.5, 0.5])
+        );
+
+        assert_eq!(
+            tiles(2, &[(3, 1)]),
+            super::bounding_box_cover(2, [0.8, 0.3], [0.8, 0.3])
+        );
+    }
+
+    #[test]
+    fn bounding_box_cover_wraps() {
+        let cover = super::bounding_box_cover(1, [-0.25, 0.0], [0.25, 0.25]);
+
+        assert_eq!(
+            vec![
+                Tile {
+                    offset: -1,
+                    level: 1,
+                    x: 3,
+                    y: 0,
+                },
+                Tile::new_at_origin(1, 0, 0),
+            ],
+            cover
+        );
+    }
+
+    #[test]
+    fn line_cover() {
+        assert_eq!(
+            tiles(2, &[(0, 0), (1, 0), (2, 0), (3, 0)]),
+            super::line_cover(2, &[[0.1, 0.1], [0.9, 0.1]])
+        );
+
+        assert_eq!(
+            tiles(2, &[(0, 0), (1, 0), (1, 1), (2, 1), (2, 2), (3, 2), (3, 3)]),
+            super::line_cover(2, &[[0.1, 0.05], [0.95, 0.9]])
+        );
+
+        assert_eq!(
+            tiles(2, &[(0, 0), (0, 1), (0, 2), (1, 2)]),
+            super::line_cover(2, &[[0.1, 0.1], [0.1, 0.6], [0.3, 0.6]])
+        );
+
+        assert_eq!(tiles(2, &[(1, 1)]), super::line_cover(2, &[[0.3, 0.3]]));
+    }
+
+    #[test]
+    fn polygon_cover() {
+        let square = [[0.1, 0.1], [0.9, 0.1], [0.9, 0.9], [0.1, 0.9]];
+
+        let cover = super::polygon_cover(2, &square, &[]);
+        assert_eq!(16, cover.len());
+        assert!(cover.contains(&Tile::new_at_origin(2, 2, 2)));
+
+        // Tiles entirely within a hole are not covered, but those partially within it are.
+        let hole = vec![[0.45, 0.45], [0.8, 0.45], [0.8, 0.8], [0.45, 0.8]];
+        let cover = super::polygon_cover(2, &square, &[hole]);
+        assert_eq!(15, cover.len());
+        assert!(!cover.contains(&Tile::new_at_origin(2, 2, 2)));
+        assert!(cover.contains(&Tile::new_at_origin(2, 1, 1)));
+    }
+
+    #[test]
+    fn polygon_cover_concave() {
+        // An L-shape, whose bounding box covers four tiles but which only touches three of them.
+        let l_shape = [
+            [0.1, 0.1],
+            [0.9, 0.1],
+            [0.9, 0.4],
+            [0.4, 0.4],
+            [0.4, 0.9],
+            [0.1, 0.9],
+        ];
+
+        assert_eq!(
+            tiles(1, &[(0, 0), (0, 1), (1, 0)]),
+            super::polygon_cover(1, &l_shape, &[])
+        );
+
+        assert_eq!(
+            4,
+            super::bounding_box_cover(1, [0.1, 0.1], [0.9, 0.9]).len()
+        );
+    }
+
+    #[test]
+    fn polygon_cover_contains_tile() {
+        let triangle = [[0.0, 0.0], [2.0, 0.0], [1.0, 1.0]];
+        let cover = super::polygon_cover(3, &triangle, &[]);
+
+        assert!(cover.contains(&Tile::new_at_origin(3, 8, 3)));
+        assert!(!cover.contains(&Tile::new_at_origin(3, 0, 7)));
+        assert!(!cover.contains(&Tile::new_at_origin(3, 15, 7)));
+    }
+
+    #[test]
+    fn non_finite_coordinates() {
+        let square = [[0.1, 0.1], [0.9, 0.1], [0.9, 0.9], [0.1, 0.9]];
+        let with_nan = [[0.1, 0.1], [f32::NAN, 0.5], [0.9, 0.1], [0.9, 0.9], [0.1, 0.9]];
+        let with_infinity = [[0.1, 0.1], [0.9, 0.1], [0.9, f32::INFINITY], [0.9, 0.9], [0.1, 0.9]];
+
+        let cover = super::polygon_cover(2, &square, &[]);
+        assert_eq!(cover, super::polygon_cover(2, &with_nan, &[]));
+        assert_eq!(cover, super::polygon_cover(2, &with_infinity, &[]));
+        assert!(super::polygon_cover(2, &[[f32::NAN, f32::NAN]], &[]).is_empty());
+
+        assert_eq!(super::line_cover(2, &square), super::line_cover(2, &with_infinity));
+
+        assert!(super::bounding_box_cover(2, [0.1, f32::NAN], [0.9, 0.9]).is_empty());
+        assert!(super::bounding_box_cover(2, [0.1, 0.1], [f32::INFINITY, 0.9]).is_empty());
+    }
+}
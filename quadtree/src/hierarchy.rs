@@ -0,0 +1,336 @@
+use std::cmp;
+use std::ops::Range;
+
+use {Tile, MAX_LEVEL};
+
+impl Tile {
+    /// The four tiles at the next level that this tile is made up of.
+    ///
+    /// Relative to `(2 * self.x, 2 * self.y)`, the children are at `(0, 0)`, `(1, 0)`, `(0, 1)`
+    /// and `(1, 1)`, in that order. This is the same order as the variants of `PositionInParent`.
+    ///
+    /// Tiles at `MAX_LEVEL` have no children.
+    pub fn children(&self) -> Vec<Tile> {
+        if self.level == MAX_LEVEL {
+            return Vec::new();
+        }
+
+        let (x, y) = (self.x * 2, self.y * 2);
+
+        vec![
+            self.with_coordinates(self.level + 1, x, y),
+            self.with_coordinates(self.level + 1, x + 1, y),
+            self.with_coordinates(self.level + 1, x, y + 1),
+            self.with_coordinates(self.level + 1, x + 1, y + 1),
+        ]
+    }
+
+    /// All the tiles at level `level` that are within this tile.
+    ///
+    /// If `level` is this tile's level, the only such tile is this tile. If `level` is less
+    /// detailed than this tile's level, there are no such tiles.
+    pub fn descendants_at_level(&self, level: u8) -> TileRange {
+        if level < self.level {
+            return TileRange::new(self.offset, level, 0..0, 0..0);
+        }
+
+        let scale = 1 << (level - self.level);
+        let x = self.x * scale;
+        let y = self.y * scale;
+
+        TileRange::new(self.offset, level, x..x + scale, y..y + scale)
+    }
+
+    /// This tile's parent, then its parent's parent, and so on until a tile at level zero.
+    pub fn ancestors(&self) -> Ancestors {
+        Ancestors {
+            tile: self.parent(),
+        }
+    }
+
+    fn with_coordinates(&self, level: u8, x: u32, y: u32) -> Tile {
+        Tile {
+            offset: self.offset,
+            level,
+            x,
+            y,
+        }
+    }
+}
+
+/// An iterator over the ancestors of a tile. See `Tile::ancestors`.
+#[derive(Debug, Clone)]
+pub struct Ancestors {
+    tile: Option<Tile>,
+}
+
+impl Iterator for Ancestors {
+    type Item = Tile;
+
+    fn next(&mut self) -> Option<Tile> {
+        let tile = self.tile.take();
+        self.tile = tile.as_ref().and_then(Tile::parent);
+        tile
+    }
+}
+
+/// A rectangle of tiles at a single level, in the copy of the world at `offset`.
+///
+/// Iterating over a range yields each tile in it, by increasing `x` and then by increasing `y`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TileRange {
+    pub offset: i16,
+    pub level: u8,
+    pub x: Range<u32>,
+    pub y: Range<u32>,
+}
+
+impl TileRange {
+    /// Create a range of tiles.
+    ///
+    /// `x` and `y` are clamped to the tiles that exist at level `level`.
+    pub fn new(offset: i16, level: u8, x: Range<u32>, y: Range<u32>) -> TileRange {
+        let width = Tile::tiles_across_width(level);
+        let height = Tile::tiles_across_height(level);
+
+        TileRange {
+            offset,
+            level,
+            x: cmp::min(x.start, width)..cmp::min(x.end, width),
+            y: cmp::min(y.start, height)..cmp::min(y.end, height),
+        }
+    }
+
+    /// Every tile at level `level`, at the origin.
+    pub fn level(level: u8) -> TileRange {
+        let width = Tile::tiles_across_width(level);
+        let height = Tile::tiles_across_height(level);
+
+        TileRange::new(0, level, 0..width, 0..height)
+    }
+
+    /// How many tiles are in this range.
+    pub fn len(&self) -> u64 {
+        let width = self.x.end.saturating_sub(self.x.start) as u64;
+        let height = self.y.end.saturating_sub(self.y.start) as u64;
+
+        width * height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, tile: &Tile) -> bool {
+        tile.offset == self.offset && tile.level == self.level && self.x.start <= tile.x
+            && tile.x < self.x.end && self.y.start <= tile.y && tile.y < self.y.end
+    }
+}
+
+impl IntoIterator for TileRange {
+    type Item = Tile;
+    type IntoIter = TileRangeIter;
+
+    fn into_iter(self) -> TileRangeIter {
+        TileRangeIter {
+            next_x: self.x.start,
+            next_y: self.y.start,
+            range: self,
+        }
+    }
+}
+
+/// An iterator over the tiles in a `TileRange`.
+#[derive(Debug, Clone)]
+pub struct TileRangeIter {
+    range: TileRange,
+    next_x: u32,
+    next_y: u32,
+}
+
+impl Iterator for TileRangeIter {
+    type Item = Tile;
+
+    fn next(&mut self) -> Option<Tile> {
+        if self.range.is_empty() || self.next_x >= self.range.x.end {
+            return None;
+        }
+
+        let tile = Tile {
+            offset: self.range.offset,
+            level: self.range.level,
+            x: self.next_x,
+            y: self.next_y,
+        };
+
+        self.next_y += 1;
+        if self.next_y == self.range.y.end {
+            self.next_y = self.range.y.start;
+            self.next_x += 1;
+        }
+
+        Some(tile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PositionInParent;
+
+    #[test]
+    fn children() {
+        let tile = Tile {
+            offset: -2,
+            level: 2,
+            x: 5,
+            y: 1,
+        };
+
+        let children = tile.children();
+        assert_eq!(
+            vec![
+                Tile {
+                    offset: -2,
+                    level: 3,
+                    x: 10,
+                    y: 2,
+                },
+                Tile {
+                    offset: -2,
+                    level: 3,
+                    x: 11,
+                    y: 2,
+                },
+                Tile {
+                    offset: -2,
+                    level: 3,
+                    x: 10,
+                    y: 3,
+                },
+                Tile {
+                    offset: -2,
+                    level: 3,
+                    x: 11,
+                    y: 3,
+                },
+            ],
+            children
+        );
+
+        let positions: Vec<_> = children
+            .iter()
+            .map(|child| child.position_in_parent().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                PositionInParent::TopLeft,
+                PositionInParent::TopRight,
+                PositionInParent::BottomLeft,
+                PositionInParent::BottomRight,
+            ],
+            positions
+        );
+
+        for child in children {
+            assert_eq!(Some(tile.clone()), child.parent());
+        }
+
+        assert!(Tile::new_at_origin(MAX_LEVEL, 0, 0).children().is_empty());
+    }
+
+    #[test]
+    fn descendants_at_level() {
+        let tile = Tile {
+            offset: 1,
+            level: 1,
+            x: 3,
+            y: 1,
+        };
+
+        assert_eq!(
+            vec![tile.clone()],
+            tile.descendants_at_level(1).into_iter().collect::<Vec<_>>()
+        );
+
+        let descendants = tile.descendants_at_level(3);
+        assert_eq!(TileRange::new(1, 3, 12..16, 4..8), descendants);
+        assert_eq!(16, descendants.len());
+        assert!(descendants.into_iter().all(|descendant| {
+            descendant.ancestors().any(|ancestor| ancestor == tile)
+        }));
+
+        assert!(tile.descendants_at_level(0).is_empty());
+    }
+
+    #[test]
+    fn ancestors() {
+        let tile = Tile {
+            offset: -1,
+            level: 3,
+            x: 9,
+            y: 4,
+        };
+
+        assert_eq!(
+            vec![
+                Tile {
+                    offset: -1,
+                    level: 2,
+                    x: 4,
+                    y: 2,
+                },
+                Tile {
+                    offset: -1,
+                    level: 1,
+                    x: 2,
+                    y: 1,
+                },
+                Tile {
+                    offset: -1,
+                    level: 0,
+                    x: 1,
+                    y: 0,
+                },
+            ],
+            tile.ancestors().collect::<Vec<_>>()
+        );
+
+        assert_eq!(0, Tile::new_at_origin(0, 1, 0).ancestors().count());
+        assert_eq!(
+            MAX_LEVEL as usize,
+            Tile::new_at_origin(MAX_LEVEL, 0, 0).ancestors().count()
+        );
+    }
+
+    #[test]
+    fn tile_range() {
+        let range = TileRange::new(0, 1, 1..3, 0..2);
+
+        assert_eq!(4, range.len());
+        assert!(range.contains(&Tile::new_at_origin(1, 2, 1)));
+        assert!(!range.contains(&Tile::new_at_origin(1, 3, 1)));
+        assert!(!range.contains(&Tile::new_at_origin(2, 2, 1)));
+
+        assert_eq!(
+            vec![
+                Tile::new_at_origin(1, 1, 0),
+                Tile::new_at_origin(1, 1, 1),
+                Tile::new_at_origin(1, 2, 0),
+                Tile::new_at_origin(1, 2, 1),
+            ],
+            range.into_iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(TileRange::new(0, 1, 2..4, 0..2), TileRange::new(0, 1, 2..10, 0..10));
+        assert!(TileRange::new(0, 1, 2..2, 0..2).into_iter().next().is_none());
+    }
+
+    #[test]
+    fn level() {
+        assert_eq!(2, TileRange::level(0).len());
+        assert_eq!(128, TileRange::level(3).len());
+        assert_eq!(128, TileRange::level(3).into_iter().count());
+        assert_eq!(1 << 61, TileRange::level(MAX_LEVEL).len());
+    }
+}
@@ -0,0 +1,240 @@
+//! Conversions between `Tile` and the tile addressing schemes used by other tools.
+//!
+//! Gaia's level-0 grid is 2×1 tiles, not the 1×1 grid of Web Mercator tile pyramids. Its grid is
+//! the same as the "global-geodetic" profile of the OSGeo Tile Map Service (TMS) specification,
+//! which is also what Leaflet-style `z/x/y` URLs address when used with an EPSG:4326 CRS. So
+//! zoom levels and x-coordinates are the same in all of these schemes; only the direction of the
+//! y-axis, and the encoding of quadkeys, differ.
+//!
+//! None of these schemes have a notion of Gaia's infinite-scroll `offset`. Converting a `Tile` to
+//! another scheme ignores its offset, and converting from another scheme produces a tile at the
+//! origin.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use {Tile, MAX_LEVEL};
+
+/// The error returned when a tile address could not be parsed.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ParseTileError {
+    message: String,
+}
+
+impl ParseTileError {
+    fn new(message: &str) -> ParseTileError {
+        ParseTileError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseTileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ParseTileError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Tile {
+    /// Get a tile from its coordinates in the TMS scheme, where `y = 0` is the southernmost row.
+    ///
+    /// Returns `None` if the coordinates are out of bounds for level `z`.
+    pub fn from_tms(z: u8, x: u32, y: u32) -> Option<Tile> {
+        if z > MAX_LEVEL || x >= Self::tiles_across_width(z) || y >= Self::tiles_across_height(z) {
+            return None;
+        }
+
+        Some(Tile::new_at_origin(z, x, y))
+    }
+
+    /// This tile's `(z, x, y)` coordinates in the TMS scheme, where `y = 0` is the southernmost
+    /// row. This is the same as Gaia's own scheme.
+    pub fn to_tms(&self) -> (u8, u32, u32) {
+        (self.level, self.x, self.y)
+    }
+
+    /// Get a tile from its coordinates in the XYZ scheme, where `y = 0` is the northernmost row.
+    ///
+    /// Returns `None` if the coordinates are out of bounds for level `z`.
+    pub fn from_xyz(z: u8, x: u32, y: u32) -> Option<Tile> {
+        if z > MAX_LEVEL || y >= Self::tiles_across_height(z) {
+            return None;
+        }
+
+        Self::from_tms(z, x, Self::tiles_across_height(z) - 1 - y)
+    }
+
+    /// This tile's `(z, x, y)` coordinates in the XYZ scheme, where `y = 0` is the northernmost
+    /// row.
+    pub fn to_xyz(&self) -> (u8, u32, u32) {
+        let y = Self::tiles_across_height(self.level) - 1 - self.y;
+        (self.level, self.x, y)
+    }
+
+    /// This tile's quadkey.
+    ///
+    /// Quadkeys have one digit per level, and each digit identifies a quadrant of the tile
+    /// described by the preceding digits, the same way as in Bing Maps: `0` is the top-left
+    /// quadrant, `1` the top-right, `2` the bottom-left and `3` the bottom-right.
+    ///
+    /// Because Gaia's level-0 grid has two tiles rather than one, the first digit is the `x` of
+    /// the level-0 ancestor of this tile, and is always `0` or `1`. A quadkey therefore has
+    /// `level + 1` digits.
+    pub fn to_quadkey(&self) -> String {
+        let (_, x, y) = self.to_xyz();
+        let mut quadkey = String::with_capacity(self.level as usize + 1);
+
+        quadkey.push(if x >> self.level == 0 { '0' } else { '1' });
+
+        for bit in (0..self.level).rev() {
+            let digit = ((x >> bit) & 1) + 2 * ((y >> bit) & 1);
+            quadkey.push((b'0' + digit as u8) as char);
+        }
+
+        quadkey
+    }
+
+    /// Get a tile from a quadkey. See `to_quadkey` for the format of quadkeys.
+    pub fn from_quadkey(quadkey: &str) -> Result<Tile, ParseTileError> {
+        let mut digits = quadkey.chars();
+
+        let mut x = match digits.next() {
+            Some('0') => 0,
+            Some('1') => 1,
+            Some(_) => return Err(ParseTileError::new("first quadkey digit must be 0 or 1")),
+            None => return Err(ParseTileError::new("quadkey is empty")),
+        };
+        let mut y = 0;
+
+        if quadkey.len() - 1 > MAX_LEVEL as usize {
+            return Err(ParseTileError::new("quadkey is too long"));
+        }
+
+        for digit in digits {
+            let digit = digit
+                .to_digit(4)
+                .ok_or_else(|| ParseTileError::new("quadkey digits must be between 0 and 3"))?;
+
+            x = (x << 1) | (digit & 1);
+            y = (y << 1) | (digit >> 1);
+        }
+
+        let level = (quadkey.len() - 1) as u8;
+        Ok(Self::from_xyz(level, x, y).unwrap())
+    }
+}
+
+/// Formats a tile as `level/x/y`, in Gaia's (and TMS's) scheme. The offset is not included.
+impl fmt::Display for Tile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.level, self.x, self.y)
+    }
+}
+
+/// Parses a tile from `level/x/y`, in Gaia's (and TMS's) scheme. The tile will be at the origin.
+impl FromStr for Tile {
+    type Err = ParseTileError;
+
+    fn from_str(s: &str) -> Result<Tile, ParseTileError> {
+        let parts: Vec<_> = s.split('/').collect();
+        if parts.len() != 3 {
+            return Err(ParseTileError::new("tile must be formatted as level/x/y"));
+        }
+
+        let level = u8::from_str(parts[0]).map_err(|_| ParseTileError::new("invalid level"))?;
+        let x = u32::from_str(parts[1]).map_err(|_| ParseTileError::new("invalid x"))?;
+        let y = u32::from_str(parts[2]).map_err(|_| ParseTileError::new("invalid y"))?;
+
+        Self::from_tms(level, x, y).ok_or_else(|| ParseTileError::new("tile is out of bounds"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tms() {
+        let tile = Tile {
+            offset: 4,
+            level: 3,
+            x: 9,
+            y: 2,
+        };
+
+        assert_eq!((3, 9, 2), tile.to_tms());
+        assert_eq!(Some(tile.to_origin()), Tile::from_tms(3, 9, 2));
+        assert_eq!(None, Tile::from_tms(3, 16, 2));
+        assert_eq!(None, Tile::from_tms(3, 9, 8));
+        assert_eq!(None, Tile::from_tms(MAX_LEVEL + 1, 0, 0));
+    }
+
+    #[test]
+    fn xyz() {
+        assert_eq!((0, 1, 0), Tile::new_at_origin(0, 1, 0).to_xyz());
+        assert_eq!((3, 9, 5), Tile::new_at_origin(3, 9, 2).to_xyz());
+
+        assert_eq!(Some(Tile::new_at_origin(3, 9, 2)), Tile::from_xyz(3, 9, 5));
+        assert_eq!(None, Tile::from_xyz(3, 9, 8));
+        assert_eq!(None, Tile::from_xyz(3, 16, 0));
+    }
+
+    #[test]
+    fn to_quadkey() {
+        assert_eq!("0", Tile::new_at_origin(0, 0, 0).to_quadkey());
+        assert_eq!("1", Tile::new_at_origin(0, 1, 0).to_quadkey());
+
+        // Top-left quadrant of the eastern hemisphere.
+        assert_eq!("10", Tile::new_at_origin(1, 2, 1).to_quadkey());
+        // Bottom-right quadrant of the western hemisphere.
+        assert_eq!("03", Tile::new_at_origin(1, 1, 0).to_quadkey());
+
+        assert_eq!("1033", Tile::new_at_origin(3, 11, 4).to_quadkey());
+    }
+
+    #[test]
+    fn from_quadkey() {
+        assert_eq!(Ok(Tile::new_at_origin(0, 1, 0)), Tile::from_quadkey("1"));
+        assert_eq!(Ok(Tile::new_at_origin(1, 1, 0)), Tile::from_quadkey("03"));
+        assert_eq!(Ok(Tile::new_at_origin(3, 11, 4)), Tile::from_quadkey("1033"));
+
+        assert!(Tile::from_quadkey("").is_err());
+        assert!(Tile::from_quadkey("2").is_err());
+        assert!(Tile::from_quadkey("014").is_err());
+        assert!(Tile::from_quadkey(&"0".repeat(MAX_LEVEL as usize + 2)).is_err());
+    }
+
+    #[test]
+    fn quadkey_round_trip() {
+        let tile = Tile::new_at_origin(MAX_LEVEL, 1_879_048_193, 12_345);
+        assert_eq!(Ok(tile.clone()), Tile::from_quadkey(&tile.to_quadkey()));
+    }
+
+    #[test]
+    fn display() {
+        let tile = Tile {
+            offset: -2,
+            level: 3,
+            x: 9,
+            y: 2,
+        };
+
+        assert_eq!("3/9/2", tile.to_string());
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!(Ok(Tile::new_at_origin(3, 9, 2)), "3/9/2".parse());
+        assert!("3/9".parse::<Tile>().is_err());
+        assert!("3/9/2/1".parse::<Tile>().is_err());
+        assert!("3/x/2".parse::<Tile>().is_err());
+        assert!("3/9/8".parse::<Tile>().is_err());
+    }
+}
@@ -1,16 +1,43 @@
 extern crate num;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+use std::i16;
 
 use num::Integer;
 
+pub mod cover;
+mod hierarchy;
+mod interop;
+pub mod projection;
+mod rtree;
+mod tree;
+
+pub use hierarchy::{Ancestors, TileRange, TileRangeIter};
+pub use interop::ParseTileError;
+pub use rtree::{BoundingBox, RTree};
+pub use tree::{Iter, QuadTree};
+
+/// The most detailed level a `Tile` can address.
+///
+/// At this level, there are `2^31` tiles across the width of the world, which is the most that
+/// fits in the `u32` coordinates of a `Tile`.
+pub const MAX_LEVEL: u8 = 30;
+
 #[derive(PartialEq, Eq, Debug, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tile {
     pub offset: i16,
     pub level: u8,
-    pub x: u8,
-    pub y: u8,
+    pub x: u32,
+    pub y: u32,
 }
 
 #[derive(PartialEq, Eq, Debug, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum PositionInParent {
     TopLeft,
     TopRight,
@@ -19,7 +46,7 @@ pub enum PositionInParent {
 }
 
 impl Tile {
-    pub fn new_at_origin(level: u8, x: u8, y: u8) -> Tile {
+    pub fn new_at_origin(level: u8, x: u32, y: u32) -> Tile {
         Tile {
             offset: 0,
             level,
@@ -28,18 +55,31 @@ impl Tile {
         }
     }
 
+    /// Get the tile at level `level` that contains `position`.
+    ///
+    /// The y-axis value will be clamped to the tiles that exist along the vertical axis, and the
+    /// offset to the range of an `i16`.
+    ///
+    /// At deep levels, tiles can be narrower than the precision of an `f32` far from the origin,
+    /// so `position` may not be able to pick out every tile there.
     pub fn enclosing_point(level: u8, position: [f32; 2]) -> Tile {
-        let offset = (position[0] / 2.0).floor();
+        // Computations are done in f64, so that they don't lose any more precision than `position`
+        // already has.
+        let (position_x, position_y) = (position[0] as f64, position[1] as f64);
+        let offset = (position_x / 2.0).floor();
 
-        let width = Self::level_width(level);
-        let x = (position[0] - offset * 2.0) / width;
-        let y = position[1] / width;
+        let width = Self::level_width_f64(level);
+        let x = ((position_x - offset * 2.0) / width).floor();
+        let y = (position_y / width).floor();
+
+        let max_x = Self::tiles_across_width(level) as f64 - 1.0;
+        let max_y = Self::tiles_across_height(level) as f64 - 1.0;
 
         Tile {
-            offset: offset as i16,
+            offset: num::clamp(offset, i16::MIN as f64, i16::MAX as f64) as i16,
             level,
-            x: x as u8,
-            y: y as u8,
+            x: num::clamp(x, 0.0, max_x) as u32,
+            y: num::clamp(y, 0.0, max_y) as u32,
         }
     }
 
@@ -74,9 +114,11 @@ impl Tile {
     }
 
     pub fn bottom_left_position(&self) -> [f32; 2] {
+        let width = Self::level_width_f64(self.level);
+
         [
-            2.0 * self.offset as f32 + self.x as f32 * self.width(),
-            self.y as f32 * self.width(),
+            (2.0 * self.offset as f64 + self.x as f64 * width) as f32,
+            (self.y as f64 * width) as f32,
         ]
     }
 
@@ -103,14 +145,25 @@ impl Tile {
 
     /// The width of tiles at level `level`.
     pub fn level_width(level: u8) -> f32 {
-        1.0 / 2.0f32.powi(level as i32)
+        Self::level_width_f64(level) as f32
     }
 
-    pub fn tiles_across_width(level: u8) -> u8 {
-        2u8.pow(level as u32 + 1)
+    fn level_width_f64(level: u8) -> f64 {
+        1.0 / 2.0f64.powi(level as i32)
     }
 
-    pub fn tiles_across_height(level: u8) -> u8 {
+    /// The number of tiles along the horizontal axis at level `level`.
+    ///
+    /// `level` must not be greater than `MAX_LEVEL`.
+    pub fn tiles_across_width(level: u8) -> u32 {
+        debug_assert!(level <= MAX_LEVEL, "level {} is too deep", level);
+        2u32.pow(level as u32 + 1)
+    }
+
+    /// The number of tiles along the vertical axis at level `level`.
+    ///
+    /// `level` must not be greater than `MAX_LEVEL`.
+    pub fn tiles_across_height(level: u8) -> u32 {
         Self::tiles_across_width(level) / 2
     }
 
@@ -119,17 +172,18 @@ impl Tile {
     ///
     /// The y-axis values will be clamped according to the number of tiles along the vertical axis.
     /// The x-axis values will wrap, incrementing or decrementing the `offset` depending on the
-    /// direction of the wrap.
-    pub fn offset_by(&self, x: i16, y: i16) -> Tile {
-        let overflow_x = self.x as i16 + x;
-        let overflow_y = self.y as i16 + y;
+    /// direction of the wrap. The `offset` saturates at the bounds of an `i16`.
+    pub fn offset_by(&self, x: i64, y: i64) -> Tile {
+        let overflow_x = self.x as i64 + x;
+        let overflow_y = self.y as i64 + y;
 
-        let width = Self::tiles_across_width(self.level) as i16;
-        let height = Self::tiles_across_height(self.level) as i16;
+        let width = Self::tiles_across_width(self.level) as i64;
+        let height = Self::tiles_across_height(self.level) as i64;
 
-        let offset = self.offset + overflow_x.div_floor(&width);
-        let x = overflow_x.mod_floor(&width) as u8;
-        let y = num::clamp(overflow_y, 0, height - 1) as u8;
+        let offset = self.offset as i64 + Integer::div_floor(&overflow_x, &width);
+        let offset = num::clamp(offset, i16::MIN as i64, i16::MAX as i64) as i16;
+        let x = Integer::mod_floor(&overflow_x, &width) as u32;
+        let y = num::clamp(overflow_y, 0, height - 1) as u32;
 
         Tile {
             offset,
@@ -277,16 +331,65 @@ mod tests {
         );
     }
 
+    #[test]
+    fn enclosing_point_deep_level() {
+        assert_eq!(
+            Tile {
+                offset: 0,
+                level: 20,
+                x: 1_572_864,
+                y: 524_288,
+            },
+            Tile::enclosing_point(20, [1.5, 0.5])
+        );
+
+        assert_eq!(
+            Tile {
+                offset: -1,
+                level: MAX_LEVEL,
+                x: 1_879_048_192,
+                y: Tile::tiles_across_height(MAX_LEVEL) - 1,
+            },
+            Tile::enclosing_point(MAX_LEVEL, [-0.25, 1.0])
+        );
+    }
+
+    #[test]
+    fn position_deep_level() {
+        let tile = Tile::new_at_origin(16, 98_304, 32_768);
+
+        assert_eq!([1.5, 0.5], tile.bottom_left_position());
+        assert_eq!(Tile::level_width(16), tile.width());
+        assert_eq!(tile, Tile::enclosing_point(16, tile.bottom_left_position()));
+    }
+
     #[test]
     fn tiles_across_width() {
         assert_eq!(2, Tile::tiles_across_width(0));
         assert_eq!(16, Tile::tiles_across_width(3));
+        assert_eq!(256, Tile::tiles_across_width(7));
+        assert_eq!(65_536, Tile::tiles_across_width(15));
+        assert_eq!(2_147_483_648, Tile::tiles_across_width(MAX_LEVEL));
     }
 
     #[test]
     fn tiles_across_height() {
         assert_eq!(1, Tile::tiles_across_height(0));
         assert_eq!(8, Tile::tiles_across_height(3));
+        assert_eq!(32_768, Tile::tiles_across_height(15));
+        assert_eq!(1_073_741_824, Tile::tiles_across_height(MAX_LEVEL));
+    }
+
+    #[test]
+    fn parent_deep_level() {
+        let tile = Tile::new_at_origin(MAX_LEVEL, 2_147_483_647, 1_073_741_823);
+        let mut ancestor = tile.clone();
+        while let Some(parent) = ancestor.parent() {
+            ancestor = parent;
+        }
+
+        assert_eq!(Tile::new_at_origin(0, 1, 0), ancestor);
+        assert_eq!(Some(PositionInParent::BottomRight), tile.position_in_parent());
     }
 
     #[test]
@@ -320,6 +423,42 @@ mod tests {
             },
             Tile::new_at_origin(1, 0, 0).offset_by(-9, -10)
         );
+
+        assert_eq!(
+            Tile {
+                offset: 0,
+                level: 15,
+                x: 65_535,
+                y: 32_767,
+            },
+            Tile::new_at_origin(15, 65_530, 32_760).offset_by(5, 300)
+        );
+
+        assert_eq!(
+            Tile {
+                offset: -1,
+                level: 15,
+                x: 65_535,
+                y: 0,
+            },
+            Tile::new_at_origin(15, 0, 10).offset_by(-1, -300)
+        );
+    }
+
+    #[test]
+    fn offset_by_saturates() {
+        let tile = Tile {
+            offset: i16::MAX,
+            level: 1,
+            x: 3,
+            y: 0,
+        };
+
+        assert_eq!(i16::MAX, tile.offset_by(1, 0).offset);
+        assert_eq!(i16::MAX, Tile::new_at_origin(1, 0, 0).offset_by(1 << 40, 0).offset);
+        assert_eq!(i16::MIN, Tile::new_at_origin(1, 0, 0).offset_by(-(1 << 40), 0).offset);
+        assert_eq!(i16::MIN, Tile::enclosing_point(1, [-1e9, 0.0]).offset);
+        assert_eq!(i16::MAX, Tile::enclosing_point(1, [1e9, 0.0]).offset);
     }
 
     #[test]
@@ -339,4 +478,19 @@ mod tests {
             }.to_origin()
         )
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let tile = Tile {
+            offset: -2,
+            level: 3,
+            x: 9,
+            y: 2,
+        };
+
+        let json = serde_json::to_string(&tile).unwrap();
+        assert_eq!(r#"{"offset":-2,"level":3,"x":9,"y":2}"#, json);
+        assert_eq!(tile, serde_json::from_str(&json).unwrap());
+    }
 }
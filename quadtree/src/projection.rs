@@ -0,0 +1,156 @@
+//! Conversions between WGS84 longitude/latitude, Gaia world coordinates, and tiles.
+//!
+//! Gaia uses an equirectangular projection. There are three coordinate spaces involved:
+//!
+//! * Longitude/latitude, in degrees, as `[lon, lat]`.
+//! * Normalized coordinates, which map the world onto `[0, 1]` along both axes. This is the space
+//!   that generated feature data (polygons and points) is stored in.
+//! * World coordinates, which are what the renderer works in. A single copy of the world spans
+//!   `[0, 2]` along the x-axis and `[0, 1]` along the y-axis. The map scrolls infinitely along the
+//!   x-axis, so there is a copy of the world for each `offset`, starting at `x = 2 * offset`.
+
+use Tile;
+
+/// Map a longitude, in degrees, onto `[0, 1]` across the width of the world.
+pub fn normalize_lon(lon: f32) -> f32 {
+    (lon + 180.0) / 360.0
+}
+
+/// Map a latitude, in degrees, onto `[0, 1]` across the height of the world.
+pub fn normalize_lat(lat: f32) -> f32 {
+    (lat + 90.0) / 180.0
+}
+
+/// The inverse of `normalize_lon`.
+pub fn denormalize_lon(x: f32) -> f32 {
+    x * 360.0 - 180.0
+}
+
+/// The inverse of `normalize_lat`.
+pub fn denormalize_lat(y: f32) -> f32 {
+    y * 180.0 - 90.0
+}
+
+/// Convert normalized coordinates into world coordinates, in the copy of the world at `offset`.
+pub fn normalized_to_world(normalized: [f32; 2], offset: i16) -> [f32; 2] {
+    [2.0 * (normalized[0] + offset as f32), normalized[1]]
+}
+
+/// Convert world coordinates into normalized coordinates, and the offset of the copy of the world
+/// they fall in.
+pub fn world_to_normalized(position: [f32; 2]) -> ([f32; 2], i16) {
+    let offset = (position[0] / 2.0).floor();
+    let x = position[0] / 2.0 - offset;
+
+    ([x, position[1]], offset as i16)
+}
+
+/// Convert a `[lon, lat]` pair into world coordinates, in the copy of the world at `offset`.
+pub fn lon_lat_to_world(lon_lat: [f32; 2], offset: i16) -> [f32; 2] {
+    normalized_to_world(
+        [normalize_lon(lon_lat[0]), normalize_lat(lon_lat[1])],
+        offset,
+    )
+}
+
+/// Convert world coordinates into a `[lon, lat]` pair, and the offset of the copy of the world
+/// they fall in.
+///
+/// Longitudes are always in `[-180, 180)`.
+pub fn world_to_lon_lat(position: [f32; 2]) -> ([f32; 2], i16) {
+    let (normalized, offset) = world_to_normalized(position);
+
+    (
+        [denormalize_lon(normalized[0]), denormalize_lat(normalized[1])],
+        offset,
+    )
+}
+
+/// Get the tile at level `level` that contains `lon_lat`, in the copy of the world at `offset`.
+pub fn lon_lat_to_tile(level: u8, lon_lat: [f32; 2], offset: i16) -> Tile {
+    Tile::enclosing_point(level, lon_lat_to_world(lon_lat, offset))
+}
+
+/// The `(min, max)` longitudes and latitudes covered by `tile`, as `[(lon_min, lon_max),
+/// (lat_min, lat_max)]`.
+///
+/// Like `world_to_lon_lat`, this ignores the offset of the tile.
+pub fn tile_lon_lat_bounds(tile: &Tile) -> [(f32, f32); 2] {
+    let bottom_left = tile.to_origin().bottom_left_position();
+    let top_right = tile.to_origin().top_right_position();
+
+    [
+        (
+            denormalize_lon(bottom_left[0] / 2.0),
+            denormalize_lon(top_right[0] / 2.0),
+        ),
+        (denormalize_lat(bottom_left[1]), denormalize_lat(top_right[1])),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(0.0, normalize_lon(-180.0));
+        assert_eq!(0.5, normalize_lon(0.0));
+        assert_eq!(1.0, normalize_lon(180.0));
+        assert_eq!(0.0, normalize_lat(-90.0));
+        assert_eq!(0.75, normalize_lat(45.0));
+
+        assert_eq!(-180.0, denormalize_lon(0.0));
+        assert_eq!(90.0, denormalize_lon(0.75));
+        assert_eq!(45.0, denormalize_lat(0.75));
+    }
+
+    #[test]
+    fn lon_lat_to_world() {
+        assert_eq!([1.0, 0.5], super::lon_lat_to_world([0.0, 0.0], 0));
+        assert_eq!([-3.5, 0.75], super::lon_lat_to_world([-90.0, 45.0], -2));
+    }
+
+    #[test]
+    fn world_to_lon_lat() {
+        assert_eq!(([0.0, 0.0], 0), super::world_to_lon_lat([1.0, 0.5]));
+        assert_eq!(([-90.0, 45.0], -2), super::world_to_lon_lat([-3.5, 0.75]));
+        assert_eq!(([-180.0, -90.0], 1), super::world_to_lon_lat([2.0, 0.0]));
+    }
+
+    #[test]
+    fn lon_lat_to_tile() {
+        assert_eq!(
+            Tile::new_at_origin(0, 1, 0),
+            super::lon_lat_to_tile(0, [10.0, 10.0], 0)
+        );
+
+        assert_eq!(
+            Tile {
+                offset: 3,
+                level: 2,
+                x: 6,
+                y: 3,
+            },
+            super::lon_lat_to_tile(2, [100.0, 50.0], 3)
+        );
+    }
+
+    #[test]
+    fn tile_lon_lat_bounds() {
+        assert_eq!(
+            [(0.0, 180.0), (-90.0, 90.0)],
+            super::tile_lon_lat_bounds(&Tile::new_at_origin(0, 1, 0))
+        );
+
+        assert_eq!(
+            [(90.0, 135.0), (45.0, 90.0)],
+            super::tile_lon_lat_bounds(&Tile {
+                offset: -5,
+                level: 2,
+                x: 6,
+                y: 3,
+            })
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f32;
+
+/// The maximum number of children of a node in an `RTree`.
+const NODE_CAPACITY: usize = 16;
+
+/// An axis-aligned rectangle.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+impl BoundingBox {
+    pub fn new(min: [f32; 2], max: [f32; 2]) -> BoundingBox {
+        BoundingBox { min, max }
+    }
+
+    /// A bounding box containing only `point`.
+    pub fn from_point(point: [f32; 2]) -> BoundingBox {
+        BoundingBox::new(point, point)
+    }
+
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.min[0] <= other.max[0] && other.min[0] <= self.max[0] && self.min[1] <= other.max[1]
+            && other.min[1] <= self.max[1]
+    }
+
+    /// The smallest bounding box containing both `self` and `other`.
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox::new(
+            [self.min[0].min(other.min[0]), self.min[1].min(other.min[1])],
+            [self.max[0].max(other.max[0]), self.max[1].max(other.max[1])],
+        )
+    }
+
+    /// The distance from `point` to the nearest point in this box, squared. This is zero if
+    /// `point` is within the box.
+    pub fn distance_squared(&self, point: [f32; 2]) -> f32 {
+        let dx = (self.min[0] - point[0]).max(0.0).max(point[0] - self.max[0]);
+        let dy = (self.min[1] - point[1]).max(0.0).max(point[1] - self.max[1]);
+
+        dx * dx + dy * dy
+    }
+
+    fn center(&self) -> [f32; 2] {
+        [
+            (self.min[0] + self.max[0]) / 2.0,
+            (self.min[1] + self.max[1]) / 2.0,
+        ]
+    }
+}
+
+/// A static R-tree, for finding values by their bounding box.
+///
+/// The tree is bulk-loaded using the Sort-Tile-Recursive algorithm, and cannot be modified after it
+/// is created.
+#[derive(Debug, Clone)]
+pub struct RTree<T> {
+    items: Vec<(BoundingBox, T)>,
+    nodes: Vec<Node>,
+    root: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    bounding_box: BoundingBox,
+    /// Indices into `items` if this is a leaf, otherwise indices into `nodes`.
+    children: Vec<usize>,
+    is_leaf: bool,
+}
+
+impl<T> RTree<T> {
+    pub fn new(items: Vec<(BoundingBox, T)>) -> RTree<T> {
+        let mut nodes = Vec::new();
+
+        let entries: Vec<_> = items
+            .iter()
+            .enumerate()
+            .map(|(index, &(bounding_box, _))| (bounding_box, index))
+            .collect();
+
+        let mut level = pack(entries, true, &mut nodes);
+        while level.len() > 1 {
+            let entries = level
+                .into_iter()
+                .map(|index| (nodes[index].bounding_box, index))
+                .collect();
+
+            level = pack(entries, false, &mut nodes);
+        }
+
+        RTree {
+            items,
+            nodes,
+            root: level.pop(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Get every value whose bounding box intersects `bounding_box`.
+    pub fn query(&self, bounding_box: &BoundingBox) -> Vec<&T> {
+        let mut result = Vec::new();
+        let mut stack: Vec<_> = self.root.into_iter().collect();
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bounding_box.intersects(bounding_box) {
+                continue;
+            }
+
+            if node.is_leaf {
+                for &item in &node.children {
+                    if self.items[item].0.intersects(bounding_box) {
+                        result.push(&self.items[item].1);
+                    }
+                }
+            } else {
+                stack.extend_from_slice(&node.children);
+            }
+        }
+
+        result
+    }
+
+    /// Get the value whose bounding box is closest to `point`, and the distance between them.
+    pub fn nearest(&self, point: [f32; 2]) -> Option<(&T, f32)> {
+        let mut queue = BinaryHeap::new();
+
+        if let Some(root) = self.root {
+            queue.push(Candidate {
+                distance_squared: self.nodes[root].bounding_box.distance_squared(point),
+                entry: Entry::Node(root),
+            });
+        }
+
+        // Candidates are visited closest-first, so the first item to come out of the queue is
+        // closer than anything that remains in it.
+        while let Some(candidate) = queue.pop() {
+            match candidate.entry {
+                Entry::Item(index) => {
+                    return Some((&self.items[index].1, candidate.distance_squared.sqrt()));
+                }
+                Entry::Node(index) => {
+                    let node = &self.nodes[index];
+
+                    for &child in &node.children {
+                        let (bounding_box, entry) = if node.is_leaf {
+                            (self.items[child].0, Entry::Item(child))
+                        } else {
+                            (self.nodes[child].bounding_box, Entry::Node(child))
+                        };
+
+                        queue.push(Candidate {
+                            distance_squared: bounding_box.distance_squared(point),
+                            entry,
+                        });
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Group `entries` into nodes of at most `NODE_CAPACITY` children, returning the indices of the
+/// created nodes.
+fn pack(
+    mut entries: Vec<(BoundingBox, usize)>,
+    is_leaf: bool,
+    nodes: &mut Vec<Node>,
+) -> Vec<usize> {
+    if entries.is_empty() {
+        return Vec::new();
+    }
+
+    let num_nodes = (entries.len() + NODE_CAPACITY - 1) / NODE_CAPACITY;
+    let num_slices = (num_nodes as f32).sqrt().ceil() as usize;
+    let slice_size = num_slices * NODE_CAPACITY;
+
+    let mut result = Vec::new();
+
+    entries.sort_by(|a, b| compare_f32(a.0.center()[0], b.0.center()[0]));
+    for slice in entries.chunks_mut(slice_size) {
+        slice.sort_by(|a, b| compare_f32(a.0.center()[1], b.0.center()[1]));
+
+        for group in slice.chunks(NODE_CAPACITY) {
+            let bounding_box = group
+                .iter()
+                .skip(1)
+                .fold(group[0].0, |acc, &(bounding_box, _)| acc.union(&bounding_box));
+
+            nodes.push(Node {
+                bounding_box,
+                children: group.iter().map(|&(_, index)| index).collect(),
+                is_leaf,
+            });
+
+            result.push(nodes.len() - 1);
+        }
+    }
+
+    result
+}
+
+fn compare_f32(a: f32, b: f32) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+#[derive(Debug)]
+enum Entry {
+    Node(usize),
+    Item(usize),
+}
+
+/// An entry in the priority queue used by `RTree::nearest`. Candidates are ordered such that the
+/// closest one is the greatest, because `BinaryHeap` is a max-heap.
+#[derive(Debug)]
+struct Candidate {
+    distance_squared: f32,
+    entry: Entry,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        compare_f32(other.distance_squared, self.distance_squared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(size: u32) -> RTree<(u32, u32)> {
+        let mut items = Vec::new();
+        for x in 0..size {
+            for y in 0..size {
+                let point = [x as f32, y as f32];
+                items.push((BoundingBox::from_point(point), (x, y)));
+            }
+        }
+
+        RTree::new(items)
+    }
+
+    #[test]
+    fn bounding_box() {
+        let a = BoundingBox::new([0.0, 0.0], [2.0, 1.0]);
+        let b = BoundingBox::new([1.0, 0.5], [3.0, 3.0]);
+        let c = BoundingBox::new([2.5, -1.0], [3.0, 0.0]);
+
+        assert!(a.intersects(&b));
+        assert!(!a.intersects(&c));
+        assert_eq!(BoundingBox::new([0.0, 0.0], [3.0, 3.0]), a.union(&b));
+
+        assert_eq!(0.0, a.distance_squared([1.0, 1.0]));
+        assert_eq!(4.0, a.distance_squared([1.0, 3.0]));
+        assert_eq!(2.0, a.distance_squared([-1.0, -1.0]));
+    }
+
+    #[test]
+    fn empty() {
+        let tree: RTree<()> = RTree::new(vec![]);
+
+        assert!(tree.is_empty());
+        assert!(tree.query(&BoundingBox::new([0.0, 0.0], [1.0, 1.0])).is_empty());
+        assert_eq!(None, tree.nearest([0.0, 0.0]));
+    }
+
+    #[test]
+    fn query() {
+        let tree = grid(50);
+        assert_eq!(2500, tree.len());
+
+        let mut result = tree.query(&BoundingBox::new([9.5, 20.0], [11.0, 21.5]));
+        result.sort();
+
+        assert_eq!(vec![&(10, 20), &(10, 21), &(11, 20), &(11, 21)], result);
+        assert!(tree.query(&BoundingBox::new([60.0, 0.0], [70.0, 10.0])).is_empty());
+    }
+
+    #[test]
+    fn query_boxes() {
+        let tree = RTree::new(vec![
+            (BoundingBox::new([0.0, 0.0], [10.0, 10.0]), "big"),
+            (BoundingBox::new([4.0, 4.0], [5.0, 5.0]), "small"),
+            (BoundingBox::new([20.0, 20.0], [21.0, 21.0]), "far"),
+        ]);
+
+        let mut result = tree.query(&BoundingBox::from_point([4.5, 4.5]));
+        result.sort();
+        assert_eq!(vec![&"big", &"small"], result);
+
+        assert_eq!(
+            vec![&"big"],
+            tree.query(&BoundingBox::new([-5.0, 9.0], [0.0, 15.0]))
+        );
+    }
+
+    #[test]
+    fn nearest() {
+        let tree = grid(50);
+
+        assert_eq!(Some((&(12, 30), 0.0)), tree.nearest([12.0, 30.0]));
+        assert_eq!(Some((&(12, 30), 0.25)), tree.nearest([12.25, 30.0]));
+        assert_eq!(Some((&(49, 0), 1.0)), tree.nearest([50.0, 0.0]));
+    }
+}
@@ -0,0 +1,495 @@
+use std::mem;
+
+use Tile;
+
+/// A sparse quadtree, associating values with tiles.
+///
+/// Only tiles that have a value, and their ancestors, take up space in the tree. Tiles are looked
+/// up without regard to their `offset`, so all copies of a tile in the infinite-scroll map share
+/// the same value. Tiles returned by the tree are at the origin, unless stated otherwise.
+#[derive(Debug, Clone)]
+pub struct QuadTree<T> {
+    roots: [Option<Box<Node<T>>>; 2],
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    value: Option<T>,
+    children: [Option<Box<Node<T>>>; 4],
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            value: None,
+            children: [None, None, None, None],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.iter().all(Option::is_none)
+    }
+}
+
+impl<T> QuadTree<T> {
+    pub fn new() -> QuadTree<T> {
+        QuadTree {
+            roots: [None, None],
+            len: 0,
+        }
+    }
+
+    /// How many tiles have a value in this tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Associate `value` with `tile`, returning the value previously associated with it, if any.
+    pub fn insert(&mut self, tile: &Tile, value: T) -> Option<T> {
+        let (root, path) = path_to(tile);
+
+        let mut node = self.roots[root].get_or_insert_with(|| Box::new(Node::new()));
+        for index in path {
+            node = node.children[index].get_or_insert_with(|| Box::new(Node::new()));
+        }
+
+        let previous = mem::replace(&mut node.value, Some(value));
+        if previous.is_none() {
+            self.len += 1;
+        }
+
+        previous
+    }
+
+    pub fn get(&self, tile: &Tile) -> Option<&T> {
+        self.node(tile).and_then(|node| node.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, tile: &Tile) -> Option<&mut T> {
+        let (root, path) = path_to(tile);
+
+        let mut node = self.roots[root].as_mut();
+        for index in path {
+            node = node.and_then(|node| node.children[index].as_mut());
+        }
+
+        node.and_then(|node| node.value.as_mut())
+    }
+
+    pub fn contains(&self, tile: &Tile) -> bool {
+        self.get(tile).is_some()
+    }
+
+    /// Remove the value associated with `tile`, if any. Descendants of `tile` are not affected.
+    pub fn remove(&mut self, tile: &Tile) -> Option<T> {
+        let (root, path) = path_to(tile);
+        let removed = remove_at(&mut self.roots[root], &path, false);
+
+        self.len -= removed.1;
+        removed.0
+    }
+
+    /// Remove the values associated with `tile` and all of its descendants, returning how many
+    /// values were removed.
+    pub fn prune(&mut self, tile: &Tile) -> usize {
+        let (root, path) = path_to(tile);
+        let removed = remove_at(&mut self.roots[root], &path, true);
+
+        self.len -= removed.1;
+        removed.1
+    }
+
+    /// Remove all values for which `f` returns false.
+    pub fn retain<F: FnMut(&Tile, &T) -> bool>(&mut self, mut f: F) {
+        let mut removed = 0;
+
+        for x in 0..2 {
+            let tile = Tile::new_at_origin(0, x, 0);
+            removed += retain_at(&mut self.roots[x as usize], &tile, &mut f);
+        }
+
+        self.len -= removed;
+    }
+
+    /// Get the value for `tile` or, if there isn't one, for its nearest ancestor that has a value.
+    ///
+    /// The returned tile has the same offset as `tile`.
+    pub fn nearest_ancestor(&self, tile: &Tile) -> Option<(Tile, &T)> {
+        let (root, path) = path_to(tile);
+
+        let mut node = self.roots[root].as_ref();
+        let mut found = None;
+        let mut level = 0;
+
+        while let Some(current) = node {
+            if let Some(ref value) = current.value {
+                found = Some((level, value));
+            }
+
+            if level == tile.level {
+                break;
+            }
+
+            node = current.children[path[level as usize]].as_ref();
+            level += 1;
+        }
+
+        found.map(|(level, value)| {
+            let shift = tile.level - level;
+            let ancestor = Tile {
+                offset: tile.offset,
+                level,
+                x: tile.x >> shift,
+                y: tile.y >> shift,
+            };
+
+            (ancestor, value)
+        })
+    }
+
+    /// Iterate over every tile that has a value.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        let mut stack = Vec::new();
+
+        for x in (0..2).rev() {
+            if let Some(ref root) = self.roots[x as usize] {
+                stack.push((Tile::new_at_origin(0, x, 0), &**root));
+            }
+        }
+
+        Iter { stack }
+    }
+
+    /// Iterate over `tile` and every descendant of `tile` that has a value.
+    pub fn iter_subtree<'a>(&'a self, tile: &Tile) -> Iter<'a, T> {
+        let stack = match self.node(tile) {
+            Some(node) => vec![(tile.to_origin(), node)],
+            None => vec![],
+        };
+
+        Iter { stack }
+    }
+
+    fn node(&self, tile: &Tile) -> Option<&Node<T>> {
+        let (root, path) = path_to(tile);
+
+        let mut node = self.roots[root].as_ref();
+        for index in path {
+            node = node.and_then(|node| node.children[index].as_ref());
+        }
+
+        node.map(|node| &**node)
+    }
+}
+
+impl<T> Default for QuadTree<T> {
+    fn default() -> QuadTree<T> {
+        QuadTree::new()
+    }
+}
+
+/// An iterator over the tiles in a `QuadTree` and their values. Tiles are visited in depth-first
+/// order, with each tile before its descendants.
+pub struct Iter<'a, T: 'a> {
+    stack: Vec<(Tile, &'a Node<T>)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Tile, &'a T);
+
+    fn next(&mut self) -> Option<(Tile, &'a T)> {
+        while let Some((tile, node)) = self.stack.pop() {
+            let children = tile.children();
+            for (child, child_node) in children.into_iter().zip(node.children.iter()).rev() {
+                if let Some(ref child_node) = *child_node {
+                    self.stack.push((child, child_node));
+                }
+            }
+
+            if let Some(ref value) = node.value {
+                return Some((tile, value));
+            }
+        }
+
+        None
+    }
+}
+
+/// The index of the root that `tile` descends from, and the indices of the children to follow from
+/// that root to get to `tile`.
+fn path_to(tile: &Tile) -> (usize, Vec<usize>) {
+    let root = (tile.x >> tile.level) as usize;
+    let path = (0..tile.level)
+        .rev()
+        .map(|shift| (((tile.x >> shift) & 1) + 2 * ((tile.y >> shift) & 1)) as usize)
+        .collect();
+
+    (root, path)
+}
+
+/// Remove the value at `path` below `slot`, and also its descendants if `subtree` is true. Nodes
+/// left empty are removed from the tree.
+///
+/// Returns the removed value at `path`, and how many values were removed in total.
+fn remove_at<T>(
+    slot: &mut Option<Box<Node<T>>>,
+    path: &[usize],
+    subtree: bool,
+) -> (Option<T>, usize) {
+    let removed = match *slot {
+        None => return (None, 0),
+        Some(ref mut node) => match path.split_first() {
+            Some((&index, rest)) => remove_at(&mut node.children[index], rest, subtree),
+            None if subtree => {
+                let count = count_values(node);
+                let value = node.value.take();
+                node.children = [None, None, None, None];
+
+                (value, count)
+            }
+            None => {
+                let value = node.value.take();
+                let count = if value.is_some() { 1 } else { 0 };
+
+                (value, count)
+            }
+        },
+    };
+
+    if is_empty_slot(slot) {
+        *slot = None;
+    }
+
+    removed
+}
+
+fn retain_at<T, F: FnMut(&Tile, &T) -> bool>(
+    slot: &mut Option<Box<Node<T>>>,
+    tile: &Tile,
+    f: &mut F,
+) -> usize {
+    let mut removed = 0;
+
+    if let Some(ref mut node) = *slot {
+        let keep = match node.value {
+            Some(ref value) => f(tile, value),
+            None => true,
+        };
+        if !keep {
+            node.value = None;
+            removed += 1;
+        }
+
+        for (child, child_slot) in tile.children().iter().zip(node.children.iter_mut()) {
+            removed += retain_at(child_slot, child, f);
+        }
+    }
+
+    if is_empty_slot(slot) {
+        *slot = None;
+    }
+
+    removed
+}
+
+fn is_empty_slot<T>(slot: &Option<Box<Node<T>>>) -> bool {
+    match *slot {
+        Some(ref node) => node.is_empty(),
+        None => false,
+    }
+}
+
+fn count_values<T>(node: &Node<T>) -> usize {
+    let own = if node.value.is_some() { 1 } else { 0 };
+    let children: usize = node.children
+        .iter()
+        .filter_map(Option::as_ref)
+        .map(|child| count_values(child))
+        .sum();
+
+    own + children
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MAX_LEVEL;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut tree = QuadTree::new();
+        let tile = Tile::new_at_origin(3, 9, 4);
+
+        assert_eq!(None, tree.insert(&tile, "a"));
+        assert_eq!(Some("a"), tree.insert(&tile, "b"));
+        assert_eq!(1, tree.len());
+
+        assert_eq!(Some(&"b"), tree.get(&tile));
+        assert!(tree.contains(&Tile {
+            offset: -4,
+            level: 3,
+            x: 9,
+            y: 4,
+        }));
+        assert!(!tree.contains(&tile.parent().unwrap()));
+        assert!(!tree.contains(&Tile::new_at_origin(3, 9, 5)));
+
+        *tree.get_mut(&tile).unwrap() = "c";
+        assert_eq!(Some(&"c"), tree.get(&tile));
+
+        assert_eq!(None, tree.remove(&tile.parent().unwrap()));
+        assert_eq!(Some("c"), tree.remove(&tile));
+        assert_eq!(None, tree.remove(&tile));
+        assert!(tree.is_empty());
+        assert!(tree.roots.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn deep_level() {
+        let mut tree = QuadTree::new();
+        let tile = Tile::new_at_origin(MAX_LEVEL, 2_000_000_000, 1_000_000_000);
+
+        tree.insert(&tile, 1);
+        tree.insert(&Tile::new_at_origin(0, 1, 0), 0);
+
+        assert_eq!(Some(&1), tree.get(&tile));
+        assert_eq!(Some((tile.clone(), &1)), tree.nearest_ancestor(&tile));
+        assert_eq!(
+            Some((Tile::new_at_origin(0, 1, 0), &0)),
+            tree.nearest_ancestor(&tile.offset_by(-1, 0))
+        );
+        assert_eq!(
+            vec![(Tile::new_at_origin(0, 1, 0), &0), (tile, &1)],
+            tree.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn nearest_ancestor() {
+        let mut tree = QuadTree::new();
+        tree.insert(&Tile::new_at_origin(0, 1, 0), "root");
+        tree.insert(&Tile::new_at_origin(2, 6, 2), "middle");
+
+        let tile = Tile {
+            offset: 7,
+            level: 4,
+            x: 25,
+            y: 9,
+        };
+
+        assert_eq!(
+            Some((
+                Tile {
+                    offset: 7,
+                    level: 2,
+                    x: 6,
+                    y: 2,
+                },
+                &"middle"
+            )),
+            tree.nearest_ancestor(&tile)
+        );
+
+        assert_eq!(
+            Some((
+                Tile {
+                    offset: 7,
+                    level: 0,
+                    x: 1,
+                    y: 0,
+                },
+                &"root"
+            )),
+            tree.nearest_ancestor(&Tile {
+                offset: 7,
+                level: 4,
+                x: 16,
+                y: 0,
+            })
+        );
+
+        assert_eq!(
+            Some((Tile::new_at_origin(2, 6, 2), &"middle")),
+            tree.nearest_ancestor(&Tile::new_at_origin(2, 6, 2))
+        );
+
+        assert_eq!(None, tree.nearest_ancestor(&Tile::new_at_origin(3, 2, 2)));
+    }
+
+    #[test]
+    fn iter() {
+        let mut tree = QuadTree::new();
+        tree.insert(&Tile::new_at_origin(2, 6, 2), 3);
+        tree.insert(&Tile::new_at_origin(1, 0, 0), 2);
+        tree.insert(&Tile::new_at_origin(0, 1, 0), 1);
+        tree.insert(&Tile::new_at_origin(2, 7, 3), 4);
+
+        assert_eq!(
+            vec![
+                (Tile::new_at_origin(1, 0, 0), &2),
+                (Tile::new_at_origin(0, 1, 0), &1),
+                (Tile::new_at_origin(2, 6, 2), &3),
+                (Tile::new_at_origin(2, 7, 3), &4),
+            ],
+            tree.iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            vec![
+                (Tile::new_at_origin(2, 6, 2), &3),
+                (Tile::new_at_origin(2, 7, 3), &4),
+            ],
+            tree.iter_subtree(&Tile::new_at_origin(1, 3, 1))
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(0, tree.iter_subtree(&Tile::new_at_origin(1, 2, 1)).count());
+    }
+
+    #[test]
+    fn prune() {
+        let mut tree = QuadTree::new();
+        tree.insert(&Tile::new_at_origin(0, 1, 0), 1);
+        tree.insert(&Tile::new_at_origin(1, 3, 1), 2);
+        tree.insert(&Tile::new_at_origin(2, 6, 2), 3);
+        tree.insert(&Tile::new_at_origin(2, 7, 3), 4);
+        tree.insert(&Tile::new_at_origin(2, 0, 0), 5);
+
+        assert_eq!(3, tree.prune(&Tile::new_at_origin(1, 3, 1)));
+        assert_eq!(2, tree.len());
+        assert!(tree.contains(&Tile::new_at_origin(0, 1, 0)));
+        assert!(!tree.contains(&Tile::new_at_origin(2, 7, 3)));
+
+        assert_eq!(0, tree.prune(&Tile::new_at_origin(1, 3, 1)));
+        assert_eq!(1, tree.prune(&Tile::new_at_origin(0, 0, 0)));
+        assert_eq!(1, tree.prune(&Tile::new_at_origin(0, 1, 0)));
+        assert!(tree.is_empty());
+        assert!(tree.roots.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn retain() {
+        let mut tree = QuadTree::new();
+        tree.insert(&Tile::new_at_origin(0, 1, 0), 1);
+        tree.insert(&Tile::new_at_origin(1, 3, 1), 2);
+        tree.insert(&Tile::new_at_origin(2, 6, 2), 3);
+        tree.insert(&Tile::new_at_origin(2, 0, 0), 4);
+
+        tree.retain(|tile, value| tile.level == 0 || value % 2 == 1);
+
+        assert_eq!(
+            vec![
+                (Tile::new_at_origin(0, 1, 0), &1),
+                (Tile::new_at_origin(2, 6, 2), &3),
+            ],
+            tree.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(2, tree.len());
+        assert!(tree.roots[0].is_none());
+    }
+}
@@ -1,5 +0,0 @@
-/// `z`-values of vertices cannot be greater than this value. This is used for view frustum
-/// culling.
-///
-/// This value must be the same as the one in terrain.glslv
-pub const Z_UPPER_BOUND: f32 = 0.03;
@@ -0,0 +1,74 @@
+/// How many meters one unit of world coordinates is, along the equator. The world is two units
+/// wide.
+const METERS_PER_WORLD_UNIT: f32 = 40_075_016.0 / 2.0;
+
+/// No terrain is higher than this many meters.
+const MAX_ELEVATION: f32 = 9_000.0;
+
+/// In compressed mode, the `z`-value that elevations approach as they increase, before
+/// exaggeration.
+const COMPRESSED_Z_UPPER_BOUND: f32 = 0.03;
+
+/// In compressed mode, how quickly elevations approach the upper bound.
+const COMPRESSION_FACTOR: f32 = 0.0001;
+
+/// How elevations are turned into heights.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElevationMode {
+    /// Heights increase more slowly the higher terrain is, so that mountains stand out without
+    /// towering over the map.
+    Compressed,
+    /// Heights are to scale with the width of the world.
+    Linear,
+}
+
+/// How elevations, in meters, are converted to `z`-values in world coordinates.
+///
+/// The terrain shader is given the conversion as a uniform, and the same conversion is used for
+/// frustum culling, for draping polygons, and for placing labels over the terrain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ElevationScale {
+    pub mode: ElevationMode,
+    /// How much to multiply heights by.
+    pub exaggeration: f32,
+}
+
+impl ElevationScale {
+    /// Convert an elevation, in meters, to a `z`-value in world coordinates.
+    pub fn to_z(&self, elevation: f32) -> f32 {
+        let uniform = self.uniform();
+        let t = 1.0 - 1.0 / (1.0 + uniform[2] * elevation);
+
+        uniform[0] * elevation + uniform[1] * t
+    }
+
+    /// `z`-values of terrain are never greater than this value. This is used for view frustum
+    /// culling.
+    pub fn max_z(&self) -> f32 {
+        self.to_z(MAX_ELEVATION)
+    }
+
+    /// The coefficients of the conversion, as passed to shaders. An elevation `e` becomes
+    /// `linear * e + compressed * (1 - 1 / (1 + compression_factor * e))`, where the uniform is
+    /// `[linear, compressed, compression_factor]`.
+    pub fn uniform(&self) -> [f32; 3] {
+        match self.mode {
+            ElevationMode::Compressed => [
+                0.0,
+                self.exaggeration * COMPRESSED_Z_UPPER_BOUND,
+                COMPRESSION_FACTOR,
+            ],
+            ElevationMode::Linear => [self.exaggeration / METERS_PER_WORLD_UNIT, 0.0, 0.0],
+        }
+    }
+}
+
+impl Default for ElevationScale {
+    /// Compressed elevations, without exaggeration.
+    fn default() -> ElevationScale {
+        ElevationScale {
+            mode: ElevationMode::Compressed,
+            exaggeration: 1.0,
+        }
+    }
+}
@@ -1 +1,8 @@
-error_chain!{}
+error_chain!{
+    errors {
+        EmptyColorRamp {
+            description("empty color ramp")
+            display("A color ramp needs at least one stop")
+        }
+    }
+}
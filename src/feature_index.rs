@@ -0,0 +1,198 @@
+use std::cmp::{self, Ordering};
+
+use gaia_assetgen::{FeaturesData, Properties};
+use gaia_quadtree::{BoundingBox, RTree};
+use gaia_quadtree::projection;
+
+use tile_chooser::MAX_VISIBLE_OFFSETS;
+
+/// A polygon or point found by a spatial query.
+#[derive(Debug, Clone)]
+pub struct Feature<'a> {
+    /// The index of the feature in the features data.
+    pub id: u64,
+    /// The copy of the world, in the infinite-scroll map, that the feature was found in.
+    pub offset: i16,
+    pub properties: &'a Properties,
+}
+
+/// A spatial index of the polygons and points in a `FeaturesData`.
+///
+/// Features are indexed in world coordinates, in the copy of the world at the origin. Queries can
+/// be made anywhere in the infinite-scroll map, and results are paired with the offset of the copy
+/// of the world they were found in.
+pub struct FeatureIndex {
+    polygons: RTree<u64>,
+    points: RTree<u64>,
+}
+
+impl FeatureIndex {
+    pub fn new(features_data: &FeaturesData) -> FeatureIndex {
+        let polygons = features_data
+            .polygons
+            .iter()
+            .enumerate()
+            .map(|(polygon_id, polygon)| {
+                let (x, y) = (polygon.bounding_box[0], polygon.bounding_box[1]);
+                let min = projection::normalized_to_world([x.0, y.0], 0);
+                let max = projection::normalized_to_world([x.1, y.1], 0);
+
+                (BoundingBox::new(min, max), polygon_id as u64)
+            })
+            .collect();
+
+        let points = features_data
+            .points
+            .iter()
+            .enumerate()
+            .map(|(point_id, point)| {
+                let position = projection::normalized_to_world(point.coordinates, 0);
+                (BoundingBox::from_point(position), point_id as u64)
+            })
+            .collect();
+
+        FeatureIndex {
+            polygons: RTree::new(polygons),
+            points: RTree::new(points),
+        }
+    }
+
+    /// Get the polygons whose bounding boxes intersect the box from `min` to `max`, as pairs of
+    /// polygon IDs and offsets.
+    ///
+    /// Like drawing, queries only look in the `MAX_VISIBLE_OFFSETS` copies of the world around the
+    /// center of the box.
+    pub fn polygons_in_box(&self, min: [f32; 2], max: [f32; 2]) -> Vec<(u64, i16)> {
+        query_box(&self.polygons, min, max)
+    }
+
+    /// Get the points within the box from `min` to `max`, as pairs of point IDs and offsets. See
+    /// `polygons_in_box` for which copies of the world are searched.
+    pub fn points_in_box(&self, min: [f32; 2], max: [f32; 2]) -> Vec<(u64, i16)> {
+        query_box(&self.points, min, max)
+    }
+
+    /// Get the point nearest to `position`, as a pair of a point ID and an offset.
+    pub fn nearest_point(&self, position: [f32; 2]) -> Option<(u64, i16)> {
+        let (normalized, offset) = projection::world_to_normalized(position);
+        let position = projection::normalized_to_world(normalized, 0);
+
+        // The nearest point may be in a neighboring copy of the world, if `position` is near the
+        // edge of its copy.
+        [-1, 0, 1]
+            .iter()
+            .filter_map(|&delta| {
+                let shifted_position = [position[0] - 2.0 * delta as f32, position[1]];
+                self.points
+                    .nearest(shifted_position)
+                    .map(|(&point_id, distance)| (point_id, offset + delta, distance))
+            })
+            .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal))
+            .map(|(point_id, offset, _)| (point_id, offset))
+    }
+}
+
+fn query_box(tree: &RTree<u64>, min: [f32; 2], max: [f32; 2]) -> Vec<(u64, i16)> {
+    let (_, min_offset) = projection::world_to_normalized(min);
+    let (_, max_offset) = projection::world_to_normalized(max);
+    let (_, center_offset) = projection::world_to_normalized([(min[0] + max[0]) / 2.0, min[1]]);
+
+    // The range is half-open, so this is `MAX_VISIBLE_OFFSETS` copies at most.
+    let min_offset = cmp::max(min_offset, center_offset.saturating_sub(MAX_VISIBLE_OFFSETS / 2));
+    let end_offset = cmp::min(
+        max_offset.saturating_add(1),
+        center_offset.saturating_add(MAX_VISIBLE_OFFSETS / 2),
+    );
+
+    let mut result = Vec::new();
+    for offset in min_offset..end_offset {
+        let shift = 2.0 * offset as f32;
+        let bounding_box = BoundingBox::new([min[0] - shift, min[1]], [max[0] - shift, max[1]]);
+
+        result.extend(tree.query(&bounding_box).into_iter().map(|&id| (id, offset)));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use gaia_assetgen::{MultiLevelPoint, MultiLevelPolygon};
+
+    use super::*;
+
+    /// Two polygons and two points, one of each near either side of the world.
+    fn index() -> FeatureIndex {
+        let polygon = |x, y| MultiLevelPolygon {
+            properties: Properties::new(),
+            bounding_box: [x, y],
+            levels: Vec::new(),
+        };
+        let point = |coordinates| MultiLevelPoint {
+            properties: Properties::new(),
+            coordinates,
+            levels: Vec::new(),
+        };
+
+        FeatureIndex::new(&FeaturesData {
+            polygons: vec![polygon((0.1, 0.2), (0.1, 0.2)), polygon((0.8, 0.9), (0.5, 0.6))],
+            points: vec![point([0.25, 0.5]), point([0.95, 0.5])],
+        })
+    }
+
+    fn sorted(mut features: Vec<(u64, i16)>) -> Vec<(u64, i16)> {
+        features.sort_by_key(|&(id, offset)| (offset, id));
+        features
+    }
+
+    #[test]
+    fn polygons_in_box() {
+        let index = index();
+
+        assert_eq!(vec![(0, 0)], index.polygons_in_box([0.0, 0.0], [1.0, 1.0]));
+        assert_eq!(vec![(1, 0), (0, 1)], sorted(index.polygons_in_box([1.5, 0.0], [2.5, 1.0])));
+        assert_eq!(vec![(0, -3)], index.polygons_in_box([-5.9, 0.0], [-5.5, 1.0]));
+        assert!(index.polygons_in_box([0.5, 0.0], [1.5, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn points_in_box() {
+        let index = index();
+
+        assert_eq!(vec![(0, 0)], index.points_in_box([0.4, 0.4], [0.6, 0.6]));
+        assert_eq!(vec![(1, -1), (0, 0)], sorted(index.points_in_box([-0.2, 0.4], [0.6, 0.6])));
+        assert!(index.points_in_box([0.4, 0.0], [0.6, 0.4]).is_empty());
+    }
+
+    #[test]
+    fn query_box_clamps_offsets() {
+        let features = index().polygons_in_box([-1000.0, 0.0], [1000.0, 1.0]);
+
+        let expected: Vec<_> = (-MAX_VISIBLE_OFFSETS / 2..MAX_VISIBLE_OFFSETS / 2)
+            .flat_map(|offset| vec![(0, offset), (1, offset)])
+            .collect();
+        assert_eq!(expected, sorted(features));
+
+        // Boxes far from the origin don't overflow the offsets.
+        assert!(index().polygons_in_box([-1e9, 0.0], [-1e9, 1.0]).is_empty());
+        assert!(index().polygons_in_box([1e9, 0.0], [1e9, 1.0]).is_empty());
+    }
+
+    #[test]
+    fn nearest_point() {
+        let index = index();
+
+        assert_eq!(Some((0, 0)), index.nearest_point([0.6, 0.5]));
+        assert_eq!(Some((0, 2)), index.nearest_point([4.6, 0.5]));
+
+        // The nearest point is across the edge of the world.
+        assert_eq!(Some((1, -1)), index.nearest_point([0.0, 0.5]));
+        assert_eq!(Some((1, 0)), index.nearest_point([2.05, 0.5]));
+
+        let empty = FeatureIndex::new(&FeaturesData {
+            polygons: Vec::new(),
+            points: Vec::new(),
+        });
+        assert_eq!(None, empty.nearest_point([0.0, 0.0]));
+    }
+}
@@ -15,13 +15,28 @@ extern crate image;
 extern crate lru_cache;
 extern crate serde_json;
 
-mod constants;
+#[cfg(test)]
+extern crate tempdir;
+
+mod elevation;
 mod errors;
+mod feature_index;
 mod render;
 mod tile_asset_getter;
+mod tile_cache;
 mod tile_chooser;
+mod tile_failures;
 mod tile_fetcher;
+mod tile_pool;
+mod tile_prefetcher;
+mod tile_source;
 
+pub use elevation::{ElevationMode, ElevationScale};
 pub use errors::{Error, ErrorKind, Result};
-pub use render::Renderer;
+pub use feature_index::Feature;
+pub use gaia_quadtree::projection;
+pub use render::{Renderer, RendererConfig, View};
 pub use render::polygon::LabelStyle;
+pub use render::terrain::{ColorRamp, Lighting, TerrainColoring};
+pub use tile_cache::TileCacheStatistics;
+pub use tile_source::{ArchiveTileSource, FileSystemTileSource, MemoryTileSource, TileSource};
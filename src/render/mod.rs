@@ -1,120 +1,531 @@
-use std::thread;
-use std::sync::mpsc;
+use std::cmp;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use cgmath::{Matrix4, Vector2};
 use gaia_assetgen::Properties;
 use gaia_quadtree::Tile;
 use gfx;
-use lru_cache::LruCache;
 
 pub mod terrain;
 pub mod polygon;
 
+use elevation::ElevationScale;
 use errors::*;
+use feature_index::Feature;
 use self::polygon::{LabelStyle, PolygonRenderer};
-use self::terrain::TerrainRenderer;
-use tile_asset_getter::{TileAssetData, TileAssets};
+use self::terrain::{Lighting, TerrainColoring, TerrainRenderer, TerrainTile};
+use tile_asset_getter::TileAssetData;
+use tile_cache::{self, TileCache, TileCacheStatistics};
 use tile_chooser;
-use tile_fetcher;
+use tile_failures::TileFailures;
+use tile_fetcher::TileFetcher;
+use tile_pool::TilePool;
+use tile_prefetcher::{self, TilePrefetcher};
+use tile_source::{FileSystemTileSource, TileSource};
+
+/// How many tiles to upload to the GPU per frame, unless configured otherwise.
+const DEFAULT_MAX_UPLOADS_PER_FRAME: usize = 4;
+
+/// How many tiles to prefetch per frame, unless configured otherwise.
+const DEFAULT_PREFETCH_BUDGET: usize = 64;
+
+/// How many sets of polygon indices to keep on the GPU, unless configured otherwise.
+const DEFAULT_POLYGON_INDICES_CACHE_CAPACITY: usize = 256;
+
+/// How long newly uploaded tiles take to fade in, in milliseconds.
+const FADE_IN_DURATION_MS: u64 = 250;
+
+/// Settings that a `Renderer` is created with.
+///
+/// By default, assets are loaded from `assets/generated`, relative to the current directory, and
+/// labels use Fira Sans.
+#[derive(Clone)]
+pub struct RendererConfig {
+    assets_dir: PathBuf,
+    elevation_scale: ElevationScale,
+    font: Vec<u8>,
+    lighting: Lighting,
+    max_uploads_per_frame: usize,
+    polygon_indices_cache_capacity: usize,
+    prefetch_budget: usize,
+    terrain_coloring: TerrainColoring,
+    tile_cache_budget_bytes: usize,
+    tile_source: Option<Arc<TileSource>>,
+}
+
+impl RendererConfig {
+    pub fn new() -> RendererConfig {
+        RendererConfig {
+            assets_dir: "assets/generated".into(),
+            elevation_scale: ElevationScale::default(),
+            font: include_bytes!("../../FiraSans-Regular.ttf").to_vec(),
+            lighting: Lighting::default(),
+            max_uploads_per_frame: DEFAULT_MAX_UPLOADS_PER_FRAME,
+            polygon_indices_cache_capacity: DEFAULT_POLYGON_INDICES_CACHE_CAPACITY,
+            prefetch_budget: DEFAULT_PREFETCH_BUDGET,
+            terrain_coloring: TerrainColoring::default(),
+            tile_cache_budget_bytes: tile_cache::default_budget_bytes(),
+            tile_source: None,
+        }
+    }
+
+    /// The directory that `gaia_assetgen` generated assets into. Features are loaded from
+    /// `features.json` in this directory, and tiles from its `tiles` directory unless a tile
+    /// source is given.
+    pub fn with_assets_dir(self, assets_dir: PathBuf) -> RendererConfig {
+        RendererConfig { assets_dir, ..self }
+    }
+
+    /// Load tiles from `tile_source`, rather than from the assets directory.
+    pub fn with_tile_source(self, tile_source: Arc<TileSource>) -> RendererConfig {
+        RendererConfig {
+            tile_source: Some(tile_source),
+            ..self
+        }
+    }
+
+    /// See `Renderer::set_elevation_scale`.
+    pub fn with_elevation_scale(self, elevation_scale: ElevationScale) -> RendererConfig {
+        RendererConfig {
+            elevation_scale,
+            ..self
+        }
+    }
+
+    /// The TrueType font to draw labels with.
+    pub fn with_font(self, font: Vec<u8>) -> RendererConfig {
+        RendererConfig { font, ..self }
+    }
+
+    /// See `Renderer::set_lighting`.
+    pub fn with_lighting(self, lighting: Lighting) -> RendererConfig {
+        RendererConfig { lighting, ..self }
+    }
+
+    /// See `Renderer::set_terrain_coloring`.
+    pub fn with_terrain_coloring(self, terrain_coloring: TerrainColoring) -> RendererConfig {
+        RendererConfig {
+            terrain_coloring,
+            ..self
+        }
+    }
+
+    /// See `Renderer::set_tile_cache_budget`.
+    pub fn with_tile_cache_budget(self, tile_cache_budget_bytes: usize) -> RendererConfig {
+        RendererConfig {
+            tile_cache_budget_bytes,
+            ..self
+        }
+    }
+
+    /// See `Renderer::set_polygon_indices_cache_capacity`.
+    pub fn with_polygon_indices_cache_capacity(
+        self,
+        polygon_indices_cache_capacity: usize,
+    ) -> RendererConfig {
+        RendererConfig {
+            polygon_indices_cache_capacity,
+            ..self
+        }
+    }
+
+    /// See `Renderer::set_prefetch_budget`.
+    pub fn with_prefetch_budget(self, prefetch_budget: usize) -> RendererConfig {
+        RendererConfig {
+            prefetch_budget,
+            ..self
+        }
+    }
+
+    /// See `Renderer::set_max_uploads_per_frame`.
+    pub fn with_max_uploads_per_frame(self, max_uploads_per_frame: usize) -> RendererConfig {
+        RendererConfig {
+            max_uploads_per_frame,
+            ..self
+        }
+    }
+}
+
+impl Default for RendererConfig {
+    fn default() -> RendererConfig {
+        RendererConfig::new()
+    }
+}
+
+/// What to draw in a frame, and how.
+pub struct View<'a> {
+    /// The model-view-projection matrix to draw the world with.
+    pub mvp: Matrix4<f32>,
+    /// How large, in pixels, the error from drawing terrain at a lower level of detail than
+    /// available may be.
+    pub max_screen_space_error: f32,
+    /// The color to fill each polygon with, or `None` to not draw it.
+    pub polygon_color_chooser: &'a Fn(&Properties) -> Option<[u8; 4]>,
+    /// How to label each point, or `None` to not label it.
+    pub label_style_chooser: &'a Fn(&Properties) -> Option<LabelStyle>,
+}
 
 pub struct Renderer<R: gfx::Resources, F: gfx::Factory<R>> {
-    asset_cache: LruCache<Tile, TileAssets<R>>,
+    asset_cache: TileCache,
+    elevation_scale: ElevationScale,
     factory: F,
+    /// Whether tiles' imagery is loaded. See `TerrainColoring::shows_imagery`.
+    load_color: bool,
+    /// The most detailed level of the tile source. See `TileSource::max_level`.
+    max_level: u8,
+    max_uploads_per_frame: usize,
+    /// Tiles that have been loaded, but not yet uploaded to the GPU.
+    pending_uploads: VecDeque<(Tile, Result<TileAssetData>)>,
     polygon_renderer: PolygonRenderer<R, F>,
-    terrain_renderer: TerrainRenderer<R, F>,
-    texture_receiver: mpsc::Receiver<(Tile, Result<TileAssetData>)>,
-    tile_sender: mpsc::Sender<Tile>,
+    terrain_renderer: TerrainRenderer<R>,
+    tile_failures: TileFailures,
+    tile_fetcher: TileFetcher,
+    tile_pool: TilePool<R>,
+    tile_prefetcher: TilePrefetcher,
 }
 
 impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> Renderer<R, F> {
+    /// Create a renderer with the default configuration, which loads assets from
+    /// `assets/generated`, relative to the current directory.
     pub fn new(factory: F) -> Result<Renderer<R, F>> {
-        let (tile_sender, tile_receiver) = mpsc::channel();
-        let (texture_sender, texture_receiver) = mpsc::channel();
+        Self::with_config(factory, RendererConfig::new())
+    }
 
-        let polygon_renderer = PolygonRenderer::new(factory.clone())?;
-        let terrain_renderer = TerrainRenderer::new(factory.clone())?;
+    /// Create a renderer that loads tiles from `source`, and is otherwise configured by default.
+    pub fn with_tile_source(factory: F, source: Arc<TileSource>) -> Result<Renderer<R, F>> {
+        Self::with_config(factory, RendererConfig::new().with_tile_source(source))
+    }
+
+    pub fn with_config(mut factory: F, config: RendererConfig) -> Result<Renderer<R, F>> {
+        let tile_source = match config.tile_source {
+            Some(ref tile_source) => tile_source.clone(),
+            None => Arc::new(FileSystemTileSource::new(config.assets_dir.join("tiles"))),
+        };
 
-        thread::Builder::new()
-            .name("tile_fetcher".to_string())
-            .spawn(move || tile_fetcher::fetch_tiles(tile_receiver, texture_sender))
-            .chain_err(|| "Error creating texture loader thread")?;
+        let polygon_renderer = PolygonRenderer::new(factory.clone(), &config)?;
+        let terrain_renderer =
+            TerrainRenderer::new(&mut factory, config.lighting, &config.terrain_coloring)?;
+        let max_level = tile_source.max_level();
+        let load_color = config.terrain_coloring.shows_imagery();
+        let tile_fetcher = TileFetcher::new(tile_source, load_color)?;
 
         Ok(Renderer {
-            asset_cache: LruCache::new(512),
+            asset_cache: TileCache::new(config.tile_cache_budget_bytes),
+            elevation_scale: config.elevation_scale,
             factory,
+            load_color,
+            max_level,
+            max_uploads_per_frame: config.max_uploads_per_frame,
+            pending_uploads: VecDeque::new(),
             polygon_renderer,
             terrain_renderer,
-            texture_receiver,
-            tile_sender,
+            tile_failures: TileFailures::new(),
+            tile_fetcher,
+            tile_pool: TilePool::new(config.tile_cache_budget_bytes),
+            tile_prefetcher: TilePrefetcher::new(config.prefetch_budget, max_level),
         })
     }
 
-    pub fn render<
-        C: gfx::CommandBuffer<R>,
-        Matrix: Into<Matrix4<f32>>,
-        Vector: Into<Vector2<f32>>,
-    >(
+    /// Set how elevations are turned into heights, including how much to exaggerate them.
+    pub fn set_elevation_scale(&mut self, elevation_scale: ElevationScale) {
+        self.elevation_scale = elevation_scale;
+    }
+
+    /// Set how terrain is shaded by the sun.
+    pub fn set_lighting(&mut self, lighting: Lighting) {
+        self.terrain_renderer.set_lighting(lighting);
+    }
+
+    /// Set whether terrain is colored by imagery, or tinted by elevation.
+    ///
+    /// Imagery isn't loaded when none of it shows through. If imagery starts to show through
+    /// again, every tile is evicted and loaded again, with its imagery.
+    pub fn set_terrain_coloring(&mut self, coloring: &TerrainColoring) -> Result<()> {
+        self.terrain_renderer.set_coloring(&mut self.factory, coloring)?;
+
+        let load_color = coloring.shows_imagery();
+        self.tile_fetcher.set_load_color(load_color)?;
+
+        if load_color && !self.load_color {
+            self.asset_cache.clear();
+        }
+        self.load_color = load_color;
+
+        Ok(())
+    }
+
+    /// Set the maximum number of tiles to prefetch per frame, in anticipation of the camera moving.
+    /// Setting this to zero disables prefetching.
+    pub fn set_prefetch_budget(&mut self, budget: usize) {
+        self.tile_prefetcher.set_budget(budget);
+    }
+
+    /// Set how much GPU memory the textures of cached tiles may take up. Least-recently used tiles
+    /// are evicted to stay within this budget.
+    ///
+    /// Tiles are stored in pages of texture arrays, which are created as tiles are cached. If the
+    /// budget shrinks to fewer pages than have been created, every tile is evicted and loaded
+    /// again.
+    pub fn set_tile_cache_budget(&mut self, budget_bytes: usize) {
+        self.asset_cache.set_budget(budget_bytes);
+
+        if self.tile_pool.set_budget(budget_bytes) {
+            self.asset_cache.clear();
+        }
+    }
+
+    /// Limit how many tiles are uploaded to the GPU per frame. At least one tile is uploaded per
+    /// frame if any are ready. The rest wait for later frames, so that a burst of loaded tiles
+    /// does not stall a frame.
+    ///
+    /// The limit is a number of tiles rather than a time, because uploads are only queued on the
+    /// encoder during `render`. The copying happens when the encoder is flushed, so it can't be
+    /// timed here.
+    pub fn set_max_uploads_per_frame(&mut self, max_uploads_per_frame: usize) {
+        self.max_uploads_per_frame = max_uploads_per_frame;
+    }
+
+    /// Set how many sets of polygon indices to keep on the GPU.
+    pub fn set_polygon_indices_cache_capacity(&mut self, capacity: usize) {
+        self.polygon_renderer.set_indices_cache_capacity(capacity);
+    }
+
+    /// Get statistics about the tile cache, and the tiles being fetched for it.
+    pub fn tile_cache_statistics(&self) -> TileCacheStatistics {
+        TileCacheStatistics {
+            pending_fetches: self.tile_fetcher.pending() + self.pending_uploads.len(),
+            ..self.asset_cache.statistics()
+        }
+    }
+
+    /// Get every tile that failed to load, and has not loaded since, along with why it failed.
+    ///
+    /// Failed tiles are retried after a while. Until they load, their ancestors are drawn in their
+    /// place. Tiles that are not retried for a few minutes, usually because they are out of view,
+    /// are forgotten.
+    pub fn tile_errors(&self) -> Vec<(&Tile, &Error)> {
+        self.tile_failures.errors(Instant::now())
+    }
+
+    /// Get the polygons whose bounding boxes intersect the box from `min` to `max`, in world
+    /// coordinates.
+    pub fn polygons_in_box<Vector: Into<Vector2<f32>>>(
+        &self,
+        min: Vector,
+        max: Vector,
+    ) -> Vec<Feature> {
+        let (min, max): (Vector2<f32>, Vector2<f32>) = (min.into(), max.into());
+        self.polygon_renderer.polygons_in_box(min.into(), max.into())
+    }
+
+    /// Get the points within the box from `min` to `max`, in world coordinates.
+    pub fn points_in_box<Vector: Into<Vector2<f32>>>(
+        &self,
+        min: Vector,
+        max: Vector,
+    ) -> Vec<Feature> {
+        let (min, max): (Vector2<f32>, Vector2<f32>) = (min.into(), max.into());
+        self.polygon_renderer.points_in_box(min.into(), max.into())
+    }
+
+    /// Get the point nearest to `position`, in world coordinates. Distances wrap around the edges
+    /// of the infinite-scroll map.
+    pub fn nearest_point<Vector: Into<Vector2<f32>>>(&self, position: Vector) -> Option<Feature> {
+        let position: Vector2<f32> = position.into();
+        self.polygon_renderer.nearest_point(position.into())
+    }
+
+    pub fn render<C: gfx::CommandBuffer<R>>(
         &mut self,
         encoder: &mut gfx::Encoder<R, C>,
         target: gfx::handle::RenderTargetView<R, gfx::format::Srgba8>,
         stencil: gfx::handle::DepthStencilView<R, gfx::format::DepthStencil>,
-        mvp: Matrix,
-        look_at: Vector,
-        camera_height: f32,
-        polygon_color_chooser: &Fn(&Properties) -> Option<[u8; 4]>,
-        label_style_chooser: &Fn(&Properties) -> Option<LabelStyle>,
-        level_chooser: &Fn(f32) -> u8,
+        view: &View,
     ) -> Result<()> {
-        // Get tiles loaded in background thread, and put them in the cache
-        for (tile, tile_texture_data) in self.texture_receiver.try_iter() {
-            let assets = tile_texture_data?.create_assets(&mut self.factory)?;
-            self.asset_cache.insert(tile.to_origin(), assets);
-        }
+        self.upload_tiles(encoder);
 
-        let mvp = mvp.into();
+        let mvp = view.mvp;
+        let max_screen_space_error = view.max_screen_space_error;
+        let (width, height, ..) = target.get_dimensions();
 
-        let (level_of_detail, tiles_to_render, tiles_to_fetch) = tile_chooser::choose_tiles(
-            level_chooser,
-            &mut self.asset_cache,
-            mvp.clone(),
-            look_at.into(),
-            camera_height,
+        let viewport_size = (width as f32, height as f32);
+
+        let desired_tiles = tile_chooser::desired_tiles(
+            self.max_level,
+            max_screen_space_error,
+            viewport_size,
+            &self.asset_cache,
+            &self.elevation_scale,
+            mvp,
         );
 
-        for tile_to_fetch in tiles_to_fetch {
-            self.tile_sender
-                .send(tile_to_fetch)
-                .chain_err(|| "Error sending tile to background thread")?;
-        }
+        let (level_of_detail, tiles_to_render, tiles_to_fetch) =
+            tile_chooser::choose_tiles(&desired_tiles, &mut self.asset_cache);
+
+        let tiles_to_prefetch = self.tile_prefetcher.tiles_to_prefetch(
+            &desired_tiles,
+            max_screen_space_error,
+            viewport_size,
+            &self.asset_cache,
+            &self.elevation_scale,
+            mvp,
+        );
+
+        let now = Instant::now();
+        let tiles_to_fetch: Vec<_> = self.tile_failures
+            .fallbacks(tiles_to_fetch, &self.asset_cache, now)
+            .into_iter()
+            .filter(|tile| !self.is_pending_upload(tile))
+            .collect();
+        let tiles_to_prefetch: Vec<_> = tiles_to_prefetch
+            .into_iter()
+            .filter(|tile| !self.tile_failures.is_waiting(tile, now))
+            .collect();
 
-        let mut polygon_metadatas = Vec::new();
-
-        for (tile, indices) in tiles_to_render {
-            let tile_assets = self.asset_cache.get_mut(&tile.to_origin()).unwrap();
-            polygon_metadatas.push((tile_assets.metadata.clone(), tile.offset));
-
-            self.terrain_renderer.render(
-                encoder,
-                target.clone(),
-                stencil.clone(),
-                &mvp,
-                tile,
-                indices,
-                tile_assets,
-            );
+        self.tile_fetcher.fetch(
+            &tiles_to_fetch,
+            &tiles_to_prefetch,
+            tile_prefetcher::view_center(&mvp),
+        )?;
+
+        for tile_to_render in &tiles_to_render {
+            self.asset_cache.touch(&tile_to_render.tile);
         }
 
+        let fade_in_duration = Duration::from_millis(FADE_IN_DURATION_MS);
+        let asset_cache = &self.asset_cache;
+
+        let terrain_tiles: Vec<_> = tiles_to_render
+            .into_iter()
+            .map(|tile_to_render| {
+                let assets = asset_cache.get(&tile_to_render.tile).unwrap();
+
+                // Newly uploaded tiles fade in from the tile that was drawn in their place before.
+                let fade = duration_ratio(assets.uploaded_at.elapsed(), fade_in_duration);
+                let fade_from = if fade < 1.0 {
+                    tile_to_render
+                        .tile
+                        .parent()
+                        .and_then(|parent| asset_cache.peek_nearest_ancestor(&parent))
+                        .map(|(ancestor, ancestor_assets)| (ancestor, ancestor_assets, fade))
+                } else {
+                    None
+                };
+
+                TerrainTile {
+                    tile_to_render,
+                    assets,
+                    fade_from,
+                }
+            })
+            .collect();
+
+        let polygon_metadatas: Vec<_> = terrain_tiles
+            .iter()
+            .map(|terrain_tile| {
+                let offset = terrain_tile.tile_to_render.tile.offset;
+                (terrain_tile.assets.metadata.clone(), offset)
+            })
+            .collect();
+
+        self.terrain_renderer.render(
+            &mut self.factory,
+            encoder,
+            target.clone(),
+            stencil.clone(),
+            &mvp,
+            &self.elevation_scale,
+            self.tile_pool.pages(),
+            &terrain_tiles,
+        )?;
+
         self.polygon_renderer.render(
             encoder,
             target,
             stencil,
             mvp,
+            &self.elevation_scale,
             level_of_detail,
             &polygon_metadatas,
-            polygon_color_chooser,
-            label_style_chooser,
+            view.polygon_color_chooser,
+            view.label_style_chooser,
         );
 
         Ok(())
     }
 }
+
+impl<R: gfx::Resources, F: gfx::Factory<R>> Renderer<R, F> {
+    /// Upload tiles loaded in background threads to the GPU, and put them in the cache, within the
+    /// per-frame limit. Tiles that failed to load are drawn using their ancestors instead.
+    fn upload_tiles<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>) {
+        self.pending_uploads.extend(self.tile_fetcher.receive());
+
+        let max_uploads = cmp::max(self.max_uploads_per_frame, 1);
+        let mut num_uploads = 0;
+
+        while num_uploads < max_uploads {
+            let (tile, tile_texture_data) = match self.pending_uploads.pop_front() {
+                Some(pending_upload) => pending_upload,
+                None => break,
+            };
+
+            // A tile that was loaded before its imagery was needed is dropped, so that it is
+            // loaded again with its imagery.
+            if let Ok(ref tile_texture_data) = tile_texture_data {
+                if self.load_color && tile_texture_data.color.is_none() {
+                    continue;
+                }
+            }
+
+            let assets = match tile_texture_data {
+                Ok(tile_texture_data) => {
+                    // The cache only evicts tiles to stay within its budget when a tile is
+                    // inserted, so the pool can be full before then.
+                    while !self.tile_pool.has_free_slot()
+                        && self.asset_cache.evict_least_recently_used()
+                    {}
+
+                    self.tile_pool.upload(&mut self.factory, encoder, tile_texture_data)
+                }
+                Err(error) => Err(error),
+            };
+
+            match assets {
+                Ok(assets) => {
+                    self.tile_failures.remove(&tile);
+                    self.asset_cache.insert(&tile, assets);
+                }
+                Err(error) => self.tile_failures.insert(&tile, error, Instant::now()),
+            }
+
+            num_uploads += 1;
+        }
+    }
+
+    fn is_pending_upload(&self, tile: &Tile) -> bool {
+        let tile = tile.to_origin();
+
+        self.pending_uploads
+            .iter()
+            .any(|&(ref pending_tile, _)| pending_tile.to_origin() == tile)
+    }
+}
+
+/// `duration` as a fraction of `total`, capped at one.
+fn duration_ratio(duration: Duration, total: Duration) -> f32 {
+    let seconds = |duration: Duration| {
+        duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+    };
+
+    (seconds(duration) / seconds(total)).min(1.0)
+}
+
+impl<R: gfx::Resources, F: gfx::Factory<R>> Drop for Renderer<R, F> {
+    fn drop(&mut self) {
+        self.tile_fetcher.stop();
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::collections::BTreeMap;
 use std::f32;
 use std::fs::File;
@@ -5,13 +6,17 @@ use std::io::BufReader;
 
 use cgmath::{Matrix4, Vector4};
 use gaia_assetgen::{FeaturesData, MultiLevelPoint, Properties, TileMetadata, MAX_LEVEL};
+use gaia_quadtree::projection;
 use gfx;
 use gfx_draping;
 use gfx_glyph;
 use lru_cache::LruCache;
 use serde_json;
 
+use elevation::ElevationScale;
 use errors::*;
+use feature_index::{Feature, FeatureIndex};
+use super::RendererConfig;
 
 pub struct PolygonRenderer<R: gfx::Resources, F: gfx::Factory<R>> {
     factory: F,
@@ -22,6 +27,7 @@ pub struct PolygonRenderer<R: gfx::Resources, F: gfx::Factory<R>> {
     polygon_properties: BTreeMap<u64, Properties>,
     points: Vec<MultiLevelPoint>,
     point_properties: BTreeMap<u64, Properties>,
+    feature_index: FeatureIndex,
     glyph_brush: gfx_glyph::GlyphBrush<'static, R, F>,
 }
 
@@ -36,11 +42,14 @@ pub struct LabelStyle<'a> {
 }
 
 impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> PolygonRenderer<R, F> {
-    pub fn new(mut factory: F) -> Result<PolygonRenderer<R, F>> {
+    pub fn new(mut factory: F, config: &RendererConfig) -> Result<PolygonRenderer<R, F>> {
+        let features_path = config.assets_dir.join("features.json");
         let features_data: FeaturesData = serde_json::from_reader(BufReader::new(
-            File::open("assets/generated/features.json")
-                .chain_err(|| "Error opening features.json")?,
-        )).chain_err(|| "Error parsing features.json")?;
+            File::open(&features_path)
+                .chain_err(|| format!("Error opening {}", features_path.display()))?,
+        )).chain_err(|| format!("Error parsing {}", features_path.display()))?;
+
+        let feature_index = FeatureIndex::new(&features_data);
 
         let mut polygon_buffers = vec![gfx_draping::PolygonBuffer::new(); MAX_LEVEL as usize + 1];
         let mut polygon_indices = BTreeMap::new();
@@ -72,9 +81,7 @@ impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> PolygonRenderer<R, F> {
 
         let draping_renderer = gfx_draping::DrapingRenderer::new(&mut factory);
 
-        let glyph_brush = gfx_glyph::GlyphBrushBuilder::using_font_bytes(include_bytes!(
-            "../../FiraSans-Regular.ttf"
-        ) as &[u8])
+        let glyph_brush = gfx_glyph::GlyphBrushBuilder::using_font_bytes(config.font.clone())
             .build(factory.clone());
 
         Ok(PolygonRenderer {
@@ -82,25 +89,69 @@ impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> PolygonRenderer<R, F> {
             draping_renderer,
             polygon_buffers,
             polygon_indices,
-            polygon_indices_cache: LruCache::new(256),
+            polygon_indices_cache: LruCache::new(config.polygon_indices_cache_capacity),
             polygon_properties,
             point_properties,
             points,
+            feature_index,
             glyph_brush,
         })
     }
 
+    pub fn set_indices_cache_capacity(&mut self, capacity: usize) {
+        self.polygon_indices_cache.set_capacity(capacity);
+    }
+
+    pub fn polygons_in_box(&self, min: [f32; 2], max: [f32; 2]) -> Vec<Feature> {
+        self.feature_index
+            .polygons_in_box(min, max)
+            .into_iter()
+            .map(|(id, offset)| Feature {
+                id,
+                offset,
+                properties: &self.polygon_properties[&id],
+            })
+            .collect()
+    }
+
+    pub fn points_in_box(&self, min: [f32; 2], max: [f32; 2]) -> Vec<Feature> {
+        self.feature_index
+            .points_in_box(min, max)
+            .into_iter()
+            .map(|(id, offset)| Feature {
+                id,
+                offset,
+                properties: &self.point_properties[&id],
+            })
+            .collect()
+    }
+
+    pub fn nearest_point(&self, position: [f32; 2]) -> Option<Feature> {
+        self.feature_index
+            .nearest_point(position)
+            .map(|(id, offset)| Feature {
+                id,
+                offset,
+                properties: &self.point_properties[&id],
+            })
+    }
+
     pub fn render<C: gfx::CommandBuffer<R>>(
         &mut self,
         encoder: &mut gfx::Encoder<R, C>,
         target: gfx::handle::RenderTargetView<R, gfx::format::Srgba8>,
         stencil: gfx::handle::DepthStencilView<R, gfx::format::DepthStencil>,
         mvp: Matrix4<f32>,
+        elevation_scale: &ElevationScale,
         level_of_detail: u8,
         positioned_polygons_to_render: &[(TileMetadata, i16)],
         polygon_color_chooser: &Fn(&Properties) -> Option<[u8; 4]>,
         label_style_chooser: &Fn(&Properties) -> Option<LabelStyle>,
     ) {
+        // Features are only simplified for the levels that assets were generated for. Deeper
+        // levels use the most detailed version available.
+        let level_of_detail = cmp::min(level_of_detail, MAX_LEVEL);
+
         // Multiple polygons can only be rendered simultaneously if they share the same color. So
         // we index polygons to render by their color using `polygon_batches`. The keys in
         // `polygon_batches` are pairs of (color, offset), where "offset" determines
@@ -142,7 +193,10 @@ impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> PolygonRenderer<R, F> {
             }
 
             let indices = self.polygon_indices_cache.get_mut(&cache_key).unwrap();
-            let (min_z, max_z) = (elevation_to_z(min_z) - 0.01, elevation_to_z(max_z) + 0.01);
+            let (min_z, max_z) = (
+                elevation_scale.to_z(min_z) - 0.01,
+                elevation_scale.to_z(max_z) + 0.01,
+            );
             let translate_x = 2.0 * offset as f32;
 
             let transform_polygon = Matrix4::from_translation([translate_x, 0.0, min_z].into())
@@ -172,13 +226,9 @@ impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> PolygonRenderer<R, F> {
                 if let Some(label_style) = label_style_chooser(point_properties) {
                     let point = &self.points[*point_id as usize];
 
-                    let z = elevation_to_z(point.levels[level_of_detail as usize]);
-                    let position = [
-                        2.0 * (point.coordinates[0] + offset as f32),
-                        point.coordinates[1],
-                        z,
-                        1.0,
-                    ];
+                    let z = elevation_scale.to_z(point.levels[level_of_detail as usize]);
+                    let world_position = projection::normalized_to_world(point.coordinates, offset);
+                    let position = [world_position[0], world_position[1], z, 1.0];
                     let screen_position: Vector4<f32> = mvp * Vector4::from(position);
 
                     let (width, height, ..) = target.get_dimensions();
@@ -244,8 +294,3 @@ impl<R: gfx::Resources, F: gfx::Factory<R> + Clone> PolygonRenderer<R, F> {
             .unwrap();
     }
 }
-
-fn elevation_to_z(elevation: f32) -> f32 {
-    let t = 1.0 - 1.0 / (1.0 + 0.0001 * elevation);
-    return t * 0.03;
-}
@@ -1,41 +1,273 @@
+use std::cmp::Ordering;
+use std::ops::Range;
+
 use cgmath::Matrix4;
 use gfx;
 use gfx::traits::FactoryExt;
 
+use elevation::ElevationScale;
 use errors::*;
 use gaia_assetgen::ELEVATION_TILE_SIZE;
 use gaia_quadtree::Tile;
 use tile_asset_getter::TileAssets;
+use tile_chooser::{Side, TileToRender, SIDES};
+use tile_pool::TilePage;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 gfx_vertex_struct!(Vertex {
     coord: [f32; 2] = "a_coord",
+    skirt: f32 = "a_skirt",
 });
 
+// Where and how to draw one square of a tile. `tile_transform` is `[left, top, width]` of the tile
+// in world coordinates, `patch_offset` is where the square starts in the tile's texture
+// coordinates, and `layers` is the layer of the tile and of the tile it is fading in from, in
+// their pages of the `TilePool`.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+gfx_vertex_struct!(Instance {
+    tile_transform: [f32; 3] = "i_tile_transform",
+    patch_offset: [f32; 2] = "i_patch_offset",
+    layers: [f32; 2] = "i_layers",
+    previous_transform: [f32; 3] = "i_previous_transform",
+    fade: f32 = "i_fade",
+});
+
+/// The most samples taken when filtering imagery that is viewed at a glancing angle.
+const MAX_ANISOTROPY: u8 = 8;
+
+/// How many texels wide the texture that a `ColorRamp` is drawn from is.
+const COLOR_RAMP_TEXTURE_SIZE: usize = 256;
+
+/// How many instances each instance buffer starts out with room for. They grow as needed.
+const INITIAL_INSTANCE_CAPACITY: usize = 32;
+
 gfx_pipeline!(pipe {
     o_color: gfx::RenderTarget<gfx::format::Srgba8> = "o_color",
     o_depth: gfx::DepthTarget<gfx::format::DepthStencil> = gfx::preset::depth::LESS_EQUAL_WRITE,
     t_color: gfx::TextureSampler<[f32; 4]> = "t_color",
     t_elevation: gfx::TextureSampler<u32> = "t_elevation",
+    t_previous_color: gfx::TextureSampler<[f32; 4]> = "t_previous_color",
+    t_previous_elevation: gfx::TextureSampler<u32> = "t_previous_elevation",
     u_mvp: gfx::Global<[[f32; 4]; 4]> = "u_mvp",
+    u_elevation_scale: gfx::Global<[f32; 3]> = "u_elevation_scale",
+    u_grid_spacing: gfx::Global<f32> = "u_grid_spacing",
+    u_sun_direction: gfx::Global<[f32; 3]> = "u_sun_direction",
+    u_ambient: gfx::Global<f32> = "u_ambient",
+    u_hillshade_strength: gfx::Global<f32> = "u_hillshade_strength",
+    t_ramp: gfx::TextureSampler<[f32; 4]> = "t_ramp",
+    u_ramp_transform: gfx::Global<[f32; 2]> = "u_ramp_transform",
+    u_tint: gfx::Global<f32> = "u_tint",
     vertex_buffer: gfx::VertexBuffer<Vertex> = (),
+    instance_buffer: gfx::InstanceBuffer<Instance> = (),
 });
 
-pub struct TerrainRenderer<R: gfx::Resources, F: gfx::Factory<R>> {
-    factory: F,
+/// How terrain is shaded by light from the sun.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lighting {
+    /// The direction that the sun is in, in degrees clockwise from north.
+    pub sun_azimuth: f32,
+    /// The height of the sun above the horizon, in degrees.
+    pub sun_altitude: f32,
+    /// How bright terrain facing away from the sun is, from zero to one.
+    pub ambient: f32,
+    /// How strongly terrain is shaded, from zero, where imagery is drawn as-is, to one.
+    pub hillshade_strength: f32,
+}
+
+impl Lighting {
+    /// A unit vector pointing towards the sun. World coordinates have x increasing eastward and y
+    /// increasing northward.
+    fn sun_direction(&self) -> [f32; 3] {
+        let (azimuth, altitude) = (self.sun_azimuth.to_radians(), self.sun_altitude.to_radians());
+
+        [
+            azimuth.sin() * altitude.cos(),
+            azimuth.cos() * altitude.cos(),
+            altitude.sin(),
+        ]
+    }
+}
+
+impl Default for Lighting {
+    /// Light from the northwest, as is conventional for shaded relief maps, but with no shading.
+    /// Imagery already has shadows in it, so shading it again would darken it.
+    fn default() -> Lighting {
+        Lighting {
+            sun_azimuth: 315.0,
+            sun_altitude: 45.0,
+            ambient: 0.35,
+            hillshade_strength: 0.0,
+        }
+    }
+}
+
+/// What colors terrain is drawn in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TerrainColoring {
+    /// Satellite imagery.
+    Imagery,
+    /// Hypsometric tinting: colors chosen by elevation from `ramp`, blended with imagery.
+    /// `imagery_blend` is how much of the imagery shows through, from zero, where only the ramp's
+    /// colors are drawn, to one, where only imagery is drawn.
+    Hypsometric { ramp: ColorRamp, imagery_blend: f32 },
+}
+
+impl TerrainColoring {
+    /// Whether any imagery shows through. If not, tiles' imagery isn't loaded, so that terrain can
+    /// be drawn from sources that don't have any.
+    pub fn shows_imagery(&self) -> bool {
+        match *self {
+            TerrainColoring::Imagery => true,
+            TerrainColoring::Hypsometric { imagery_blend, .. } => imagery_blend > 0.0,
+        }
+    }
+}
+
+impl Default for TerrainColoring {
+    fn default() -> TerrainColoring {
+        TerrainColoring::Imagery
+    }
+}
+
+/// A mapping from elevations, in meters, to colors. Colors are interpolated linearly between
+/// stops. Elevations below the lowest stop or above the highest get that stop's color.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorRamp {
+    stops: Vec<(f32, [u8; 4])>,
+}
+
+impl ColorRamp {
+    /// Create a ramp from `(elevation, color)` stops, given in any order. Fails with
+    /// `ErrorKind::EmptyColorRamp` if there are no stops.
+    pub fn new(mut stops: Vec<(f32, [u8; 4])>) -> Result<ColorRamp> {
+        if stops.is_empty() {
+            bail!(ErrorKind::EmptyColorRamp);
+        }
+
+        stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        Ok(ColorRamp { stops })
+    }
+
+    /// The color of `elevation`.
+    pub fn color(&self, elevation: f32) -> [u8; 4] {
+        let (first, last) = (self.stops[0], self.stops[self.stops.len() - 1]);
+        if elevation <= first.0 {
+            return first.1;
+        }
+
+        for pair in self.stops.windows(2) {
+            let ((below, below_color), (above, above_color)) = (pair[0], pair[1]);
+
+            if elevation <= above {
+                let t = (elevation - below) / (above - below);
+
+                let mut color = [0; 4];
+                for i in 0..4 {
+                    let channel = below_color[i] as f32 * (1.0 - t) + above_color[i] as f32 * t;
+                    color[i] = channel.round() as u8;
+                }
+
+                return color;
+            }
+        }
+
+        last.1
+    }
+
+    /// The elevations of the lowest and highest stops.
+    fn range(&self) -> (f32, f32) {
+        (self.stops[0].0, self.stops[self.stops.len() - 1].0)
+    }
+}
+
+impl Default for ColorRamp {
+    /// Lowlands in green, through tan and brown hills, to snow-capped peaks.
+    fn default() -> ColorRamp {
+        ColorRamp {
+            stops: vec![
+                (0.0, [72, 130, 64, 255]),
+                (500.0, [150, 175, 95, 255]),
+                (1500.0, [200, 170, 115, 255]),
+                (3000.0, [140, 105, 80, 255]),
+                (4500.0, [245, 245, 245, 255]),
+            ],
+        }
+    }
+}
+
+/// A square of a cached tile to draw, along with the tile that it is fading in from.
+pub struct TerrainTile<'a> {
+    pub tile_to_render: TileToRender,
+    pub assets: &'a TileAssets,
+    /// The ancestor that was drawn in the tile's place, its assets, and how far the tile has
+    /// faded in from it, from zero to one.
+    pub fade_from: Option<(Tile, &'a TileAssets, f32)>,
+}
+
+pub struct TerrainRenderer<R: gfx::Resources> {
+    /// An instance buffer for each draw in a frame. Every draw's instances start at the beginning
+    /// of a buffer, because drawing from an offset into a buffer needs a base instance, which
+    /// OpenGL only supports from version 4.2.
+    instance_buffers: Vec<gfx::handle::Buffer<R, Instance>>,
+    lighting: Lighting,
+    /// The colors of the current `TerrainColoring`'s ramp, along with how to get from an
+    /// elevation to a texture coordinate in the ramp and how much of the ramp's color to use.
+    /// See `set_coloring`.
+    ramp: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+    ramp_transform: [f32; 2],
+    tint: f32,
+    /// The indices for each width of square that a tile can be drawn in, indexed by the base two
+    /// logarithm of the width.
+    patch_indices: Vec<PatchIndices<R>>,
     pso: gfx::PipelineState<R, pipe::Meta>,
-    sampler: gfx::handle::Sampler<R>,
+    /// Filters between mip levels, so that distant imagery doesn't shimmer.
+    color_sampler: gfx::handle::Sampler<R>,
+    elevation_sampler: gfx::handle::Sampler<R>,
+    ramp_sampler: gfx::handle::Sampler<R>,
     vertex_buffer: gfx::handle::Buffer<R, Vertex>,
 }
 
-impl<R: gfx::Resources, F: gfx::Factory<R>> TerrainRenderer<R, F> {
-    pub fn new(mut factory: F) -> Result<TerrainRenderer<R, F>> {
-        let sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(
+/// The indices to draw a square of the elevation grid whose top left corner is at the origin.
+///
+/// The shader moves each square to where it belongs in its tile's grid, so the same indices are
+/// used for squares of the same width anywhere in the grid.
+struct PatchIndices<R: gfx::Resources> {
+    buffer: gfx::IndexBuffer<R>,
+    surface: Range<u32>,
+    /// The skirts along each combination of sides, indexed by a bitmask where bit `i` is set if
+    /// there is a skirt along `SIDES[i]`.
+    skirts: Vec<Range<u32>>,
+}
+
+impl<R: gfx::Resources> TerrainRenderer<R> {
+    pub fn new<F: gfx::Factory<R>>(
+        factory: &mut F,
+        lighting: Lighting,
+        coloring: &TerrainColoring,
+    ) -> Result<TerrainRenderer<R>> {
+        let color_sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(
+            gfx::texture::FilterMethod::Anisotropic(MAX_ANISOTROPY),
+            gfx::texture::WrapMode::Clamp,
+        ));
+        let elevation_sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(
+            gfx::texture::FilterMethod::Bilinear,
+            gfx::texture::WrapMode::Clamp,
+        ));
+        let ramp_sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(
             gfx::texture::FilterMethod::Bilinear,
             gfx::texture::WrapMode::Clamp,
         ));
 
-        let vertex_buffer = Self::create_vertex_buffer(&mut factory);
+        let (ramp, ramp_transform, tint) = Self::create_ramp(factory, coloring)?;
+
+        let vertex_buffer = Self::create_vertex_buffer(factory);
+
+        let mut patch_indices = Vec::new();
+        let mut width = 1;
+        while width < ELEVATION_TILE_SIZE {
+            patch_indices.push(Self::create_patch_indices(factory, width));
+            width *= 2;
+        }
 
         let pso = factory
             .create_pipeline_simple(
@@ -46,60 +278,421 @@ impl<R: gfx::Resources, F: gfx::Factory<R>> TerrainRenderer<R, F> {
             .chain_err(|| "Could not create pipeline")?;
 
         Ok(TerrainRenderer {
-            factory,
+            instance_buffers: Vec::new(),
+            lighting,
+            ramp,
+            ramp_transform,
+            tint,
+            patch_indices,
             pso,
-            sampler,
+            color_sampler,
+            elevation_sampler,
+            ramp_sampler,
             vertex_buffer,
         })
     }
 
-    pub fn render<C: gfx::CommandBuffer<R>>(
+    pub fn set_lighting(&mut self, lighting: Lighting) {
+        self.lighting = lighting;
+    }
+
+    pub fn set_coloring<F: gfx::Factory<R>>(
+        &mut self,
+        factory: &mut F,
+        coloring: &TerrainColoring,
+    ) -> Result<()> {
+        let (ramp, ramp_transform, tint) = Self::create_ramp(factory, coloring)?;
+
+        self.ramp = ramp;
+        self.ramp_transform = ramp_transform;
+        self.tint = tint;
+
+        Ok(())
+    }
+
+    /// Draw every tile, with one instanced draw for the surfaces and one for the skirts of each
+    /// combination of the pages that the tile and the tile it is fading in from are in, width and
+    /// skirts.
+    pub fn render<C: gfx::CommandBuffer<R>, F: gfx::Factory<R>>(
         &mut self,
+        factory: &mut F,
         encoder: &mut gfx::Encoder<R, C>,
         target: gfx::handle::RenderTargetView<R, gfx::format::Srgba8>,
         stencil: gfx::handle::DepthStencilView<R, gfx::format::DepthStencil>,
         mvp: &Matrix4<f32>,
-        tile: Tile,
-        indices: Vec<u32>,
-        tile_assets: &TileAssets<R>,
+        elevation_scale: &ElevationScale,
+        pages: &[TilePage<R>],
+        tiles: &[TerrainTile],
+    ) -> Result<()> {
+        // Instances are sorted so that the tiles in each draw are next to each other.
+        let mut instances: Vec<_> = tiles
+            .iter()
+            .map(|terrain_tile| {
+                let tile_to_render = &terrain_tile.tile_to_render;
+                let width_index = tile_to_render.width.trailing_zeros() as usize;
+
+                let page = terrain_tile.assets.slot.page() as usize;
+                let previous_page = match terrain_tile.fade_from {
+                    Some((_, ancestor_assets, _)) => ancestor_assets.slot.page() as usize,
+                    None => page,
+                };
+
+                let key = (page, previous_page, width_index, skirt_mask(terrain_tile));
+                (key, instance(terrain_tile))
+            })
+            .collect();
+        instances.sort_by_key(|&(key, _)| key);
+
+        for (i, run) in runs(&instances, |&(key, _)| key).into_iter().enumerate() {
+            let (page, previous_page, width_index, skirt_mask) = instances[run.start].0;
+            let (page, previous_page) = (&pages[page], &pages[previous_page]);
+            let run_instances: Vec<_> = instances[run]
+                .iter()
+                .map(|&(_, instance)| instance)
+                .collect();
+
+            let instance_buffer = self.instance_buffer(factory, i, run_instances.len())?;
+            encoder
+                .update_buffer(&instance_buffer, &run_instances, 0)
+                .chain_err(|| "Could not update instance buffer")?;
+
+            let data = pipe::Data {
+                o_color: target.clone(),
+                o_depth: stencil.clone(),
+                t_color: (page.color.clone(), self.color_sampler.clone()),
+                t_elevation: (page.elevation.clone(), self.elevation_sampler.clone()),
+                t_previous_color: (previous_page.color.clone(), self.color_sampler.clone()),
+                t_previous_elevation: (
+                    previous_page.elevation.clone(),
+                    self.elevation_sampler.clone(),
+                ),
+                u_mvp: (*mvp).into(),
+                u_elevation_scale: elevation_scale.uniform(),
+                u_grid_spacing: 1.0 / (ELEVATION_TILE_SIZE - 1) as f32,
+                u_sun_direction: self.lighting.sun_direction(),
+                u_ambient: self.lighting.ambient,
+                u_hillshade_strength: self.lighting.hillshade_strength,
+                t_ramp: (self.ramp.clone(), self.ramp_sampler.clone()),
+                u_ramp_transform: self.ramp_transform,
+                u_tint: self.tint,
+                vertex_buffer: self.vertex_buffer.clone(),
+                instance_buffer,
+            };
+
+            let patch_indices = &self.patch_indices[width_index];
+            let num_instances = run_instances.len() as u32;
+            self.draw(encoder, &data, patch_indices, &patch_indices.surface, num_instances);
+            self.draw(
+                encoder,
+                &data,
+                patch_indices,
+                &patch_indices.skirts[skirt_mask],
+                num_instances,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Draw the range of indices `indices` for each of the first `num_instances` instances in the
+    /// instance buffer.
+    fn draw<C: gfx::CommandBuffer<R>>(
+        &self,
+        encoder: &mut gfx::Encoder<R, C>,
+        data: &pipe::Data<R>,
+        patch_indices: &PatchIndices<R>,
+        indices: &Range<u32>,
+        num_instances: u32,
     ) {
+        if indices.start == indices.end {
+            return;
+        }
+
         let slice = gfx::Slice {
-            start: 0,
-            end: indices.len() as u32,
+            start: indices.start,
+            end: indices.end,
             base_vertex: 0,
-            instances: None,
-            buffer: self.factory.create_index_buffer(&indices[..]),
+            instances: Some((num_instances, 0)),
+            buffer: patch_indices.buffer.clone(),
         };
 
-        let offset_by = tile.top_left_position();
-        let offset = Matrix4::from_translation([offset_by[0], offset_by[1], 0.0].into());
-        let scale = Matrix4::from_nonuniform_scale(tile.width(), -tile.width(), 1.0);
+        encoder.draw(&slice, &self.pso, data);
+    }
+
+    /// The instance buffer for the `index`th draw of a frame, grown to hold at least
+    /// `num_instances` instances.
+    fn instance_buffer<F: gfx::Factory<R>>(
+        &mut self,
+        factory: &mut F,
+        index: usize,
+        num_instances: usize,
+    ) -> Result<gfx::handle::Buffer<R, Instance>> {
+        if index == self.instance_buffers.len() {
+            let buffer = Self::create_instance_buffer(factory, INITIAL_INSTANCE_CAPACITY)?;
+            self.instance_buffers.push(buffer);
+        }
 
-        let mvp = mvp * offset * scale;
+        if num_instances > self.instance_buffers[index].len() {
+            let capacity = num_instances.next_power_of_two();
+            self.instance_buffers[index] = Self::create_instance_buffer(factory, capacity)?;
+        }
 
-        let data = pipe::Data {
-            o_color: target.clone(),
-            o_depth: stencil.clone(),
-            t_color: (tile_assets.color.clone(), self.sampler.clone()),
-            t_elevation: (tile_assets.elevation.clone(), self.sampler.clone()),
-            u_mvp: mvp.into(),
-            vertex_buffer: self.vertex_buffer.clone(),
+        Ok(self.instance_buffers[index].clone())
+    }
+
+    /// Create the texture for `coloring`'s ramp, and get the elevation `e` at which the texture
+    /// is sampled at `transform[0] * e + transform[1]`, and how much of the ramp's color to use.
+    ///
+    /// Imagery is drawn without any tint, so it gets an unused ramp with a single texel.
+    fn create_ramp<F: gfx::Factory<R>>(
+        factory: &mut F,
+        coloring: &TerrainColoring,
+    ) -> Result<(gfx::handle::ShaderResourceView<R, [f32; 4]>, [f32; 2], f32)> {
+        let (texels, transform, tint) = match *coloring {
+            TerrainColoring::Imagery => (vec![[0; 4]], [0.0, 0.5], 0.0),
+            TerrainColoring::Hypsometric {
+                ref ramp,
+                imagery_blend,
+            } => {
+                let (min, max) = ramp.range();
+                let size = COLOR_RAMP_TEXTURE_SIZE as f32;
+
+                let texels = (0..COLOR_RAMP_TEXTURE_SIZE)
+                    .map(|i| ramp.color(min + (max - min) * i as f32 / (size - 1.0)))
+                    .collect();
+
+                // The lowest and highest stops are at the centers of the first and last texels.
+                let transform = if max > min {
+                    let scale = (size - 1.0) / (size * (max - min));
+                    [scale, 0.5 / size - scale * min]
+                } else {
+                    [0.0, 0.5]
+                };
+
+                (texels, transform, 1.0 - imagery_blend)
+            }
         };
 
-        encoder.draw(&slice, &self.pso, &data);
+        let (_, view) = factory
+            .create_texture_immutable::<gfx::format::Srgba8>(
+                gfx::texture::Kind::D1(texels.len() as u16),
+                gfx::texture::Mipmap::Provided,
+                &[&texels],
+            )
+            .chain_err(|| "Could not create color ramp texture")?;
+
+        Ok((view, transform, tint))
+    }
+
+    fn create_instance_buffer<F: gfx::Factory<R>>(
+        factory: &mut F,
+        capacity: usize,
+    ) -> Result<gfx::handle::Buffer<R, Instance>> {
+        factory
+            .create_buffer(
+                capacity,
+                gfx::buffer::Role::Vertex,
+                gfx::memory::Usage::Dynamic,
+                gfx::memory::Bind::empty(),
+            )
+            .chain_err(|| "Could not create instance buffer")
     }
 
-    fn create_vertex_buffer(factory: &mut F) -> gfx::handle::Buffer<R, Vertex> {
+    /// The vertex buffer has a vertex for each point of the elevation grid, followed by a second
+    /// copy of each of those vertices that is used for skirts.
+    fn create_vertex_buffer<F: gfx::Factory<R>>(factory: &mut F) -> gfx::handle::Buffer<R, Vertex> {
         let mut vertex_data = vec![];
-        for y in 0..ELEVATION_TILE_SIZE {
-            for x in 0..ELEVATION_TILE_SIZE {
-                let coord_scale = (ELEVATION_TILE_SIZE - 1) as f32;
-                vertex_data.push(Vertex {
-                    coord: [x as f32 / coord_scale, y as f32 / coord_scale],
-                });
+        for &skirt in &[0.0, 1.0] {
+            for y in 0..ELEVATION_TILE_SIZE {
+                for x in 0..ELEVATION_TILE_SIZE {
+                    let coord_scale = (ELEVATION_TILE_SIZE - 1) as f32;
+                    vertex_data.push(Vertex {
+                        coord: [x as f32 / coord_scale, y as f32 / coord_scale],
+                        skirt,
+                    });
+                }
             }
         }
 
         factory.create_vertex_buffer(&vertex_data)
     }
+
+    fn create_patch_indices<F: gfx::Factory<R>>(factory: &mut F, width: u32) -> PatchIndices<R> {
+        let mut index_data = Vec::new();
+        for x in 0..width {
+            for y in 0..width {
+                let a = (x + 0) + (y + 0) * ELEVATION_TILE_SIZE;
+                let b = (x + 0) + (y + 1) * ELEVATION_TILE_SIZE;
+                let c = (x + 1) + (y + 0) * ELEVATION_TILE_SIZE;
+                let d = (x + 1) + (y + 1) * ELEVATION_TILE_SIZE;
+
+                index_data.extend_from_slice(&[a, b, c, c, b, d]);
+            }
+        }
+
+        let surface = 0..index_data.len() as u32;
+
+        let mut skirts = Vec::new();
+        for skirt_mask in 0..1 << SIDES.len() {
+            let start = index_data.len() as u32;
+
+            for (i, &side) in SIDES.iter().enumerate() {
+                if skirt_mask & (1 << i) != 0 {
+                    add_skirt_indices(width, side, &mut index_data);
+                }
+            }
+
+            skirts.push(start..index_data.len() as u32);
+        }
+
+        PatchIndices {
+            buffer: factory.create_index_buffer(&index_data[..]),
+            surface,
+            skirts,
+        }
+    }
+}
+
+fn instance(terrain_tile: &TerrainTile) -> Instance {
+    let tile_to_render = &terrain_tile.tile_to_render;
+    let tile = &tile_to_render.tile;
+    let layer = terrain_tile.assets.slot.layer();
+
+    // A tile that is fading in is blended with the ancestor that was drawn in its place, whose
+    // textures are sampled at `offset + scale * coord`, where the previous transform is
+    // `[offset_x, offset_y, scale]`.
+    let (previous_layer, previous_transform, fade) = match terrain_tile.fade_from {
+        Some((ref ancestor, ancestor_assets, fade)) => {
+            let (position, ancestor_position) =
+                (tile.top_left_position(), ancestor.top_left_position());
+
+            let transform = [
+                (position[0] - ancestor_position[0]) / ancestor.width(),
+                (ancestor_position[1] - position[1]) / ancestor.width(),
+                tile.width() / ancestor.width(),
+            ];
+
+            (ancestor_assets.slot.layer(), transform, fade)
+        }
+        None => (layer, [0.0, 0.0, 1.0], 1.0),
+    };
+
+    let position = tile.top_left_position();
+    let grid_width = (ELEVATION_TILE_SIZE - 1) as f32;
+
+    Instance {
+        tile_transform: [position[0], position[1], tile.width()],
+        patch_offset: [
+            tile_to_render.left_x as f32 / grid_width,
+            tile_to_render.top_y as f32 / grid_width,
+        ],
+        layers: [layer as f32, previous_layer as f32],
+        previous_transform,
+        fade,
+    }
+}
+
+/// Where a neighbor is drawn from a tile at a different level, the edges of the two tiles don't
+/// line up, which would leave cracks between them. These are covered by a skirt hanging down from
+/// the edge. Returns which sides need skirts, as an index into `PatchIndices::skirts`.
+///
+/// A tile that is fading in has its elevation blended toward its ancestor's, so its edges don't
+/// line up with any neighbor's until it has faded in. It has skirts on every side.
+fn skirt_mask(terrain_tile: &TerrainTile) -> usize {
+    if terrain_tile.fade_from.is_some() {
+        return (1 << SIDES.len()) - 1;
+    }
+
+    let tile_to_render = &terrain_tile.tile_to_render;
+    let level = tile_to_render.tile.level;
+    let mut skirt_mask = 0;
+
+    for (i, levels) in tile_to_render.neighbor_levels.iter().enumerate() {
+        match *levels {
+            Some((min, max)) if min != level || max != level => skirt_mask |= 1 << i,
+            _ => {}
+        }
+    }
+
+    skirt_mask
+}
+
+/// Split `items` into runs of consecutive items with the same key.
+fn runs<T, K: PartialEq, F: Fn(&T) -> K>(items: &[T], key: F) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+
+    for i in 1..items.len() + 1 {
+        if i == items.len() || key(&items[i]) != key(&items[start]) {
+            runs.push(start..i);
+            start = i;
+        }
+    }
+
+    runs
+}
+
+fn add_skirt_indices(width: u32, side: Side, index_data: &mut Vec<u32>) {
+    // The tile's grid has its first row at the top, so the bottom side is its last row.
+    let edge: Vec<_> = (0..width + 1)
+        .map(|i| match side {
+            Side::Left => (0, i),
+            Side::Right => (width, i),
+            Side::Bottom => (i, width),
+            Side::Top => (i, 0),
+        })
+        .map(|(x, y)| x + y * ELEVATION_TILE_SIZE)
+        .collect();
+
+    let skirt_offset = ELEVATION_TILE_SIZE * ELEVATION_TILE_SIZE;
+    for pair in edge.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let (c, d) = (a + skirt_offset, b + skirt_offset);
+
+        index_data.extend_from_slice(&[a, c, b, b, c, d]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp() -> ColorRamp {
+        ColorRamp::new(vec![
+            (1000.0, [200, 100, 0, 255]),
+            (0.0, [0, 0, 0, 255]),
+            (2000.0, [200, 200, 200, 0]),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn color_ramp_sorts_stops() {
+        assert_eq!((0.0, 2000.0), ramp().range());
+        assert_eq!([200, 100, 0, 255], ramp().color(1000.0));
+    }
+
+    #[test]
+    fn color_ramp_interpolates() {
+        assert_eq!([100, 50, 0, 255], ramp().color(500.0));
+        assert_eq!([50, 25, 0, 255], ramp().color(250.0));
+        assert_eq!([200, 150, 100, 128], ramp().color(1500.0));
+    }
+
+    #[test]
+    fn color_ramp_clamps() {
+        assert_eq!([0, 0, 0, 255], ramp().color(-100.0));
+        assert_eq!([200, 200, 200, 0], ramp().color(9000.0));
+
+        let single_stop = ColorRamp::new(vec![(10.0, [1, 2, 3, 4])]).unwrap();
+        assert_eq!([1, 2, 3, 4], single_stop.color(0.0));
+        assert_eq!([1, 2, 3, 4], single_stop.color(20.0));
+    }
+
+    #[test]
+    fn empty_color_ramp() {
+        match ColorRamp::new(Vec::new()) {
+            Err(Error(ErrorKind::EmptyColorRamp, _)) => {}
+            result => panic!("Expected an empty color ramp error, got {:?}", result),
+        }
+    }
 }
@@ -1,108 +1,179 @@
-use std::io::BufReader;
-use std::fs::File;
+use std::time::Instant;
 
-use byteorder::{LittleEndian, ReadBytesExt};
-use gaia_assetgen::{TileMetadata, ELEVATION_OFFSET, ELEVATION_TILE_SIZE, IMAGERY_TILE_SIZE};
+use byteorder::{ByteOrder, LittleEndian};
+use gaia_assetgen::{TileMetadata, ELEVATION_OFFSET, IMAGERY_TILE_SIZE};
+use gaia_assetgen::texture;
 use gaia_quadtree::Tile;
-use gfx;
 use image;
 use serde_json;
 
 use errors::*;
+use tile_pool::TileSlot;
+use tile_source::TileSource;
 
-pub struct TileAssets<R: gfx::Resources> {
-    pub color: gfx::handle::ShaderResourceView<R, [f32; 4]>,
-    pub elevation: gfx::handle::ShaderResourceView<R, u32>,
+pub struct TileAssets {
+    /// Where the tile's textures are in the `TilePool`.
+    pub slot: TileSlot,
     pub metadata: TileMetadata,
+    /// How much GPU memory the textures take up.
+    pub size_in_bytes: usize,
+    /// When the textures were uploaded to the GPU.
+    pub uploaded_at: Instant,
 }
 
 pub struct TileAssetData {
-    pub color: Vec<u8>,
+    /// The tile's imagery as RGBA pixels, with every level of its mip chain, largest first, or
+    /// `None` if it wasn't loaded because it isn't drawn.
+    pub color: Option<Vec<Vec<u8>>>,
     pub elevation: Vec<u16>,
     pub metadata: TileMetadata,
 }
 
 impl TileAssetData {
-    pub fn new(tile: &Tile) -> Result<TileAssetData> {
+    /// Load a tile's assets from `source`, skipping its imagery unless `load_color` is set.
+    pub fn new(source: &TileSource, tile: &Tile, load_color: bool) -> Result<TileAssetData> {
+        let color = if load_color {
+            Some(get_color_data(source, tile)?)
+        } else {
+            None
+        };
+
         Ok(TileAssetData {
-            color: get_color_data(tile)?,
-            elevation: get_elevation_data(tile)?,
-            metadata: get_metadata(tile)?,
+            color,
+            elevation: get_elevation_data(source, tile)?,
+            metadata: get_metadata(source, tile)?,
         })
     }
+}
 
-    pub fn create_assets<R: gfx::Resources, F: gfx::Factory<R>>(
-        self,
-        factory: &mut F,
-    ) -> Result<TileAssets<R>> {
-        let color_texture_kind = gfx::texture::Kind::D2(
-            IMAGERY_TILE_SIZE as u16,
-            IMAGERY_TILE_SIZE as u16,
-            gfx::texture::AaMode::Single,
-        );
-        let (_, color_texture_view) = factory
-            .create_texture_immutable_u8::<gfx::format::Srgba8>(
-                color_texture_kind,
-                gfx::texture::Mipmap::Provided,
-                &[self.color.as_slice()],
-            )
-            .chain_err(|| "Could not create color texture")?;
-
-        let elevation_texture_kind = gfx::texture::Kind::D2(
-            ELEVATION_TILE_SIZE as u16,
-            ELEVATION_TILE_SIZE as u16,
-            gfx::texture::AaMode::Single,
-        );
-        let (_, elevation_texture_view) = factory
-            .create_texture_immutable::<(gfx::format::R16, gfx::format::Uint)>(
-                elevation_texture_kind,
-                gfx::texture::Mipmap::Provided,
-                &[self.elevation.as_slice()],
-            )
-            .chain_err(|| "Could not create color texture")?;
-
-        Ok(TileAssets {
-            color: color_texture_view,
-            elevation: elevation_texture_view,
-            metadata: self.metadata,
-        })
+/// Load a tile's imagery, preferring its compressed form if the source has one, because that
+/// already has a mip chain and is quicker to decode. Otherwise, the mip chain is generated here.
+///
+/// The imagery is always decompressed, because gfx can only fill compressed textures when they
+/// are created, not one layer at a time like the `TilePool` does.
+fn get_color_data(source: &TileSource, tile: &Tile) -> Result<Vec<Vec<u8>>> {
+    if let Some(data) = source.compressed_color(tile)? {
+        let (compression, levels) = texture::split_compressed(&data, IMAGERY_TILE_SIZE)
+            .ok_or("Compressed tile image data is corrupt")?;
+
+        return Ok(texture::decompress(&levels, IMAGERY_TILE_SIZE, compression));
     }
+
+    let data = source.color(tile)?;
+
+    let img = image::load_from_memory(&data).chain_err(|| "Error reading tile image data")?;
+    let levels = texture::mip_chain(img.to_rgba().into_raw(), IMAGERY_TILE_SIZE)
+        .ok_or("Tile image is the wrong size")?;
+
+    Ok(levels)
 }
 
-fn get_color_data(tile: &Tile) -> Result<Vec<u8>> {
-    let path = format!(
-        "assets/generated/tiles/{}_{}_{}.jpg",
-        tile.level, tile.x, tile.y
-    );
+fn get_elevation_data(source: &TileSource, tile: &Tile) -> Result<Vec<u16>> {
+    let data = source.elevation(tile)?;
 
-    let img = image::open(path).chain_err(|| "Error reading tile image data")?;
-    Ok(img.to_rgba().into_raw())
+    Ok(data.chunks(2)
+        .filter(|chunk| chunk.len() == 2)
+        .map(|chunk| LittleEndian::read_u16(chunk).saturating_sub(ELEVATION_OFFSET))
+        .collect())
 }
 
-fn get_elevation_data(tile: &Tile) -> Result<Vec<u16>> {
-    let path = format!(
-        "assets/generated/tiles/{}_{}_{}.gray",
-        tile.level, tile.x, tile.y
-    );
+fn get_metadata(source: &TileSource, tile: &Tile) -> Result<TileMetadata> {
+    let data = source.metadata(tile)?;
+
+    Ok(serde_json::from_slice(&data).chain_err(|| "Error parsing tile metadata")?)
+}
 
-    let mut file =
-        BufReader::new(File::open(&path).chain_err(|| "Error opening tile elevation data")?);
+#[cfg(test)]
+mod tests {
+    use gaia_assetgen::ELEVATION_TILE_SIZE;
+    use image::png::PNGEncoder;
+    use image::ColorType;
 
-    let mut buf = Vec::new();
-    while let Ok(elevation) = file.read_u16::<LittleEndian>() {
-        buf.push(elevation.saturating_sub(ELEVATION_OFFSET));
+    use tile_source::MemoryTileSource;
+
+    use super::*;
+
+    fn tile() -> Tile {
+        Tile::new_at_origin(1, 2, 0)
     }
 
-    Ok(buf)
-}
+    fn image(size: u32) -> Vec<u8> {
+        let pixels = vec![200; 3 * size as usize * size as usize];
 
-fn get_metadata(tile: &Tile) -> Result<TileMetadata> {
-    let path = format!(
-        "assets/generated/tiles/{}_{}_{}.json",
-        tile.level, tile.x, tile.y
-    );
+        let mut data = Vec::new();
+        PNGEncoder::new(&mut data)
+            .encode(&pixels, size, size, ColorType::RGB(8))
+            .unwrap();
+        data
+    }
 
-    let file = BufReader::new(File::open(path).chain_err(|| "Error reading tile metadata")?);
+    fn elevation() -> Vec<u8> {
+        let num_points = ELEVATION_TILE_SIZE as usize * ELEVATION_TILE_SIZE as usize;
 
-    Ok(serde_json::from_reader(file).chain_err(|| "Error parsing tile metadata")?)
+        let mut data = vec![0; 2 * num_points];
+        for chunk in data.chunks_mut(2) {
+            LittleEndian::write_u16(chunk, ELEVATION_OFFSET + 1000);
+        }
+        data
+    }
+
+    fn metadata() -> Vec<u8> {
+        br#"{"min_elevation": 1000, "max_elevation": 1000, "polygons": [4], "points": []}"#.to_vec()
+    }
+
+    fn memory_source(color: Vec<u8>, metadata: Vec<u8>) -> MemoryTileSource {
+        let mut source = MemoryTileSource::new();
+        source.insert(&tile(), color, elevation(), metadata);
+        source
+    }
+
+    #[test]
+    fn loads_tile() {
+        let source = memory_source(image(IMAGERY_TILE_SIZE), metadata());
+        let data = TileAssetData::new(&source, &tile(), true).unwrap();
+
+        let color = data.color.unwrap();
+        assert_eq!(texture::mip_sizes(IMAGERY_TILE_SIZE).len(), color.len());
+        assert_eq!(&[200, 200, 200, 255], &color[0][..4]);
+
+        assert_eq!(
+            (ELEVATION_TILE_SIZE * ELEVATION_TILE_SIZE) as usize,
+            data.elevation.len()
+        );
+        assert!(data.elevation.iter().all(|&elevation| elevation == 1000));
+
+        assert_eq!(1000, data.metadata.max_elevation);
+        assert_eq!(vec![4], data.metadata.polygons);
+    }
+
+    #[test]
+    fn skips_color() {
+        let source = memory_source(b"not an image".to_vec(), metadata());
+        let data = TileAssetData::new(&source, &tile(), false).unwrap();
+
+        assert!(data.color.is_none());
+    }
+
+    #[test]
+    fn missing_tile() {
+        let source = memory_source(image(IMAGERY_TILE_SIZE), metadata());
+
+        assert!(TileAssetData::new(&source, &Tile::new_at_origin(1, 3, 0), true).is_err());
+    }
+
+    #[test]
+    fn malformed_color() {
+        let source = memory_source(b"not an image".to_vec(), metadata());
+        assert!(TileAssetData::new(&source, &tile(), true).is_err());
+
+        let source = memory_source(image(IMAGERY_TILE_SIZE - 1), metadata());
+        assert!(TileAssetData::new(&source, &tile(), true).is_err());
+    }
+
+    #[test]
+    fn malformed_metadata() {
+        let source = memory_source(image(IMAGERY_TILE_SIZE), b"{".to_vec());
+
+        assert!(TileAssetData::new(&source, &tile(), true).is_err());
+    }
 }
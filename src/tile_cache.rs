@@ -0,0 +1,320 @@
+use std::usize;
+
+use gaia_quadtree::{QuadTree, Tile};
+use lru_cache::LruCache;
+
+use tile_asset_getter::TileAssets;
+use tile_pool;
+
+/// How many tiles the default budget for a `TileCache` fits.
+///
+/// The `TilePool` only allocates textures for tiles as they are cached, so this is how much GPU
+/// memory the cache can grow to: about 630 MB. That leaves room for everything else on GPUs with
+/// 1 GB of memory, which 512 tiles, at about 1.26 GB, would not.
+pub const DEFAULT_BUDGET_TILES: usize = 256;
+
+/// The default budget for a `TileCache`, in bytes. See `DEFAULT_BUDGET_TILES`.
+pub fn default_budget_bytes() -> usize {
+    DEFAULT_BUDGET_TILES * tile_pool::tile_size_in_bytes()
+}
+
+/// Counters describing how well the tile cache is working.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TileCacheStatistics {
+    /// How many times a tile to draw was already in cache.
+    pub hits: u64,
+    /// How many times a tile to draw was not in cache, and had to be fetched.
+    pub misses: u64,
+    /// How many tiles have been removed from cache to stay within its budget.
+    pub evictions: u64,
+    /// How many tiles are waiting to be loaded, or are being loaded.
+    pub pending_fetches: usize,
+    /// How many tiles are in cache.
+    pub resident_tiles: usize,
+    /// How much GPU memory the textures of the tiles in cache take up.
+    pub resident_bytes: usize,
+    /// How much GPU memory the cache is allowed to use.
+    pub budget_bytes: usize,
+}
+
+/// The tiles that have been loaded onto the GPU.
+///
+/// Tiles are kept in a `QuadTree`, so that the nearest loaded ancestor of a tile can be looked up
+/// directly. When the textures of the tiles in cache would take up more memory than the cache's
+/// budget, the least-recently used tiles are evicted.
+pub struct TileCache {
+    tiles: QuadTree<TileAssets>,
+    usage: LruCache<Tile, ()>,
+    budget_bytes: usize,
+    statistics: TileCacheStatistics,
+}
+
+impl TileCache {
+    pub fn new(budget_bytes: usize) -> TileCache {
+        TileCache {
+            tiles: QuadTree::new(),
+            usage: LruCache::new(usize::MAX),
+            budget_bytes,
+            statistics: TileCacheStatistics {
+                budget_bytes,
+                ..TileCacheStatistics::default()
+            },
+        }
+    }
+
+    /// Change the budget, evicting tiles if they no longer fit in it.
+    pub fn set_budget(&mut self, budget_bytes: usize) {
+        self.budget_bytes = budget_bytes;
+        self.statistics.budget_bytes = budget_bytes;
+        self.evict_to_fit(0);
+    }
+
+    pub fn insert(&mut self, tile: &Tile, assets: TileAssets) {
+        let tile = tile.to_origin();
+
+        if let Some(previous) = self.tiles.remove(&tile) {
+            self.usage.remove(&tile);
+            self.remove_statistics(&previous);
+        }
+
+        self.evict_to_fit(assets.size_in_bytes);
+
+        self.statistics.resident_tiles += 1;
+        self.statistics.resident_bytes += assets.size_in_bytes;
+
+        self.usage.insert(tile.clone(), ());
+        self.tiles.insert(&tile, assets);
+    }
+
+    pub fn contains(&self, tile: &Tile) -> bool {
+        self.tiles.contains(tile)
+    }
+
+    /// Like `contains`, but counts as a hit or miss in the cache's statistics.
+    pub fn lookup(&mut self, tile: &Tile) -> bool {
+        let contains = self.contains(tile);
+
+        if contains {
+            self.statistics.hits += 1;
+        } else {
+            self.statistics.misses += 1;
+        }
+
+        contains
+    }
+
+    pub fn get(&self, tile: &Tile) -> Option<&TileAssets> {
+        self.tiles.get(tile)
+    }
+
+    /// Mark `tile` as the most recently used tile, so that it is the last to be evicted.
+    pub fn touch(&mut self, tile: &Tile) {
+        self.usage.get_mut(&tile.to_origin());
+    }
+
+    /// Like `nearest_ancestor`, but also gets the ancestor's assets, and does not count as a use of
+    /// the ancestor.
+    pub fn peek_nearest_ancestor(&self, tile: &Tile) -> Option<(Tile, &TileAssets)> {
+        self.tiles.nearest_ancestor(tile)
+    }
+
+    /// Get `tile` if it is loaded, or otherwise its nearest ancestor that is loaded.
+    ///
+    /// The returned tile has the same offset as `tile`.
+    pub fn nearest_ancestor(&mut self, tile: &Tile) -> Option<Tile> {
+        let ancestor = self.tiles.nearest_ancestor(tile).map(|(ancestor, _)| ancestor);
+
+        if let Some(ref ancestor) = ancestor {
+            self.usage.get_mut(&ancestor.to_origin());
+        }
+
+        ancestor
+    }
+
+    /// Evict the least-recently used tile, returning whether there was one to evict.
+    pub fn evict_least_recently_used(&mut self) -> bool {
+        let evicted = match self.usage.remove_lru() {
+            Some((evicted, ())) => evicted,
+            None => return false,
+        };
+
+        if let Some(assets) = self.tiles.remove(&evicted) {
+            self.remove_statistics(&assets);
+            self.statistics.evictions += 1;
+        }
+
+        true
+    }
+
+    /// Remove every tile from the cache. This does not count as evicting them.
+    pub fn clear(&mut self) {
+        self.tiles = QuadTree::new();
+        self.usage.clear();
+        self.statistics.resident_tiles = 0;
+        self.statistics.resident_bytes = 0;
+    }
+
+    /// The cache's statistics. `pending_fetches` is always zero, as the cache does not know about
+    /// fetches.
+    pub fn statistics(&self) -> TileCacheStatistics {
+        self.statistics
+    }
+
+    /// Evict least-recently used tiles until `extra_bytes` more can be added without going over
+    /// budget, or the cache is empty.
+    fn evict_to_fit(&mut self, extra_bytes: usize) {
+        while self.statistics.resident_bytes + extra_bytes > self.budget_bytes {
+            if !self.evict_least_recently_used() {
+                break;
+            }
+        }
+    }
+
+    fn remove_statistics(&mut self, assets: &TileAssets) {
+        self.statistics.resident_tiles -= 1;
+        self.statistics.resident_bytes -= assets.size_in_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use gaia_assetgen::TileMetadata;
+
+    use tile_pool::TileSlot;
+
+    use super::*;
+
+    fn tile(x: u32) -> Tile {
+        Tile::new_at_origin(3, x, 0)
+    }
+
+    fn assets(size_in_bytes: usize) -> TileAssets {
+        TileAssets {
+            slot: TileSlot::detached(),
+            metadata: TileMetadata {
+                min_elevation: 0,
+                max_elevation: 0,
+                polygons: Vec::new(),
+                points: Vec::new(),
+            },
+            size_in_bytes,
+            uploaded_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn counts_resident_tiles() {
+        let mut cache = TileCache::new(100);
+        cache.insert(&tile(0), assets(10));
+        cache.insert(&tile(1), assets(20));
+
+        let statistics = cache.statistics();
+        assert_eq!(2, statistics.resident_tiles);
+        assert_eq!(30, statistics.resident_bytes);
+        assert_eq!(100, statistics.budget_bytes);
+
+        // Replacing a tile isn't an eviction.
+        cache.insert(&tile(1), assets(5));
+
+        let statistics = cache.statistics();
+        assert_eq!(2, statistics.resident_tiles);
+        assert_eq!(15, statistics.resident_bytes);
+        assert_eq!(0, statistics.evictions);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let mut cache = TileCache::new(100);
+        cache.insert(&tile(0), assets(10));
+
+        assert!(cache.lookup(&tile(0)));
+        assert!(cache.lookup(&Tile {
+            offset: -1,
+            ..tile(0)
+        }));
+        assert!(!cache.lookup(&tile(1)));
+
+        // Only lookups count.
+        assert!(cache.contains(&tile(0)));
+
+        let statistics = cache.statistics();
+        assert_eq!(2, statistics.hits);
+        assert_eq!(1, statistics.misses);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TileCache::new(30);
+        cache.insert(&tile(0), assets(10));
+        cache.insert(&tile(1), assets(10));
+        cache.insert(&tile(2), assets(10));
+
+        cache.touch(&tile(0));
+        cache.insert(&tile(3), assets(10));
+
+        assert!(cache.contains(&tile(0)));
+        assert!(!cache.contains(&tile(1)));
+        assert!(cache.contains(&tile(2)));
+        assert!(cache.contains(&tile(3)));
+
+        // Finding a tile as an ancestor counts as using it.
+        assert_eq!(Some(tile(2)), cache.nearest_ancestor(&tile(2).children()[0]));
+        cache.insert(&tile(4), assets(20));
+
+        assert!(!cache.contains(&tile(0)));
+        assert!(cache.contains(&tile(2)));
+        assert!(!cache.contains(&tile(3)));
+
+        let statistics = cache.statistics();
+        assert_eq!(3, statistics.evictions);
+        assert_eq!(2, statistics.resident_tiles);
+        assert_eq!(30, statistics.resident_bytes);
+    }
+
+    #[test]
+    fn keeps_tile_larger_than_budget() {
+        let mut cache = TileCache::new(10);
+        cache.insert(&tile(0), assets(10));
+        cache.insert(&tile(1), assets(50));
+
+        assert!(!cache.contains(&tile(0)));
+        assert!(cache.contains(&tile(1)));
+        assert_eq!(50, cache.statistics().resident_bytes);
+    }
+
+    #[test]
+    fn set_budget_evicts() {
+        let mut cache = TileCache::new(100);
+        for x in 0..4 {
+            cache.insert(&tile(x), assets(10));
+        }
+
+        cache.set_budget(25);
+
+        assert!(!cache.contains(&tile(0)));
+        assert!(!cache.contains(&tile(1)));
+        assert!(cache.contains(&tile(2)));
+
+        let statistics = cache.statistics();
+        assert_eq!(2, statistics.evictions);
+        assert_eq!(20, statistics.resident_bytes);
+        assert_eq!(25, statistics.budget_bytes);
+    }
+
+    #[test]
+    fn clear() {
+        let mut cache = TileCache::new(100);
+        cache.insert(&tile(0), assets(10));
+        cache.clear();
+
+        assert!(!cache.contains(&tile(0)));
+        assert!(!cache.evict_least_recently_used());
+
+        let statistics = cache.statistics();
+        assert_eq!(0, statistics.resident_tiles);
+        assert_eq!(0, statistics.resident_bytes);
+        assert_eq!(0, statistics.evictions);
+    }
+}
@@ -1,84 +1,375 @@
-use cgmath::{Matrix4, Vector2};
+use std::{cmp, f32, i16, iter};
+use std::collections::HashMap;
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector2, Vector4};
 use collision::{Aabb3, Frustum, Relation};
 use gaia_assetgen::ELEVATION_TILE_SIZE;
 use gaia_quadtree::{PositionInParent, Tile};
-use gfx;
-use lru_cache::LruCache;
 
-use constants::Z_UPPER_BOUND;
-use tile_asset_getter::TileAssets;
+use elevation::ElevationScale;
+use tile_cache::TileCache;
+
+/// The maximum number of copies of the world, in the infinite-scroll map, to look for visible tiles
+/// in.
+pub const MAX_VISIBLE_OFFSETS: i16 = 8;
+
+/// A side of a tile.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Left,
+    Right,
+    Bottom,
+    Top,
+}
+
+/// Every side of a tile, in the order used by `NeighborLevels`.
+pub const SIDES: [Side; 4] = [Side::Left, Side::Right, Side::Bottom, Side::Top];
+
+/// For each side of a tile, in the order of `SIDES`, the lowest and highest levels of the cached
+/// tiles drawn next to that side, or `None` if nothing is drawn next to it.
+pub type NeighborLevels = [Option<(u8, u8)>; 4];
+
+impl Side {
+    /// The direction to move in to get to the neighbor on this side, in tiles.
+    fn direction(self) -> (i64, i64) {
+        match self {
+            Side::Left => (-1, 0),
+            Side::Right => (1, 0),
+            Side::Bottom => (0, -1),
+            Side::Top => (0, 1),
+        }
+    }
+
+    fn opposite(self) -> Side {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+            Side::Bottom => Side::Top,
+            Side::Top => Side::Bottom,
+        }
+    }
+
+    /// Whether `child` is along this side of its parent.
+    fn is_along(self, child: &Tile) -> bool {
+        match self {
+            Side::Left => child.x % 2 == 0,
+            Side::Right => child.x % 2 == 1,
+            Side::Bottom => child.y % 2 == 0,
+            Side::Top => child.y % 2 == 1,
+        }
+    }
+}
 
-/// Gets tiles that can be rendered immediately, and tiles that should be fetched.
+/// A square of a cached tile's elevation grid, to draw in place of a desired tile.
+pub struct TileToRender {
+    /// The cached tile to draw part of.
+    pub tile: Tile,
+    /// The grid coordinates of the top left corner of the square.
+    pub left_x: u32,
+    pub top_y: u32,
+    /// The width of the square, in grid cells.
+    pub width: u32,
+    /// The levels of the cached tiles drawn next to the square, so that any cracks between them
+    /// and this tile can be covered.
+    pub neighbor_levels: NeighborLevels,
+}
+
+/// Gets tiles that can be rendered immediately, and tiles that should be fetched, to draw
+/// `desired_tiles`. See `desired_tiles` for how these are chosen.
+///
+/// The returned level is the most detailed level among the desired tiles.
 ///
-/// `tiles_to_render` contains pairs of positioned tiles, and the indices to use for that tile.
-/// These tiles are already in cache, and can be rendered immediately.
+/// `tiles_to_render` contains the parts of cached tiles to draw for each desired tile. These tiles
+/// are already in cache, and can be rendered immediately.
 ///
 /// `tiles_to_fetch` is the desired tiles for the current camera position that are not in cache.
 /// These should be fetched and put into cache, so that future calls to this function can use them.
-pub fn choose_tiles<R: gfx::Resources>(
-    level_chooser: &Fn(f32) -> u8,
-    texture_cache: &mut LruCache<Tile, TileAssets<R>>,
-    mvp: Matrix4<f32>,
-    look_at: Vector2<f32>,
-    camera_height: f32,
-) -> (u8, Vec<(Tile, Vec<u32>)>, Vec<Tile>) {
+pub fn choose_tiles(
+    desired_tiles: &[Tile],
+    texture_cache: &mut TileCache,
+) -> (u8, Vec<TileToRender>, Vec<Tile>) {
     let mut tiles_to_render = vec![];
     let mut tiles_to_fetch = vec![];
 
-    let desired_level = level_chooser(camera_height);
+    let level_of_detail = desired_tiles.iter().map(|tile| tile.level).max().unwrap_or(0);
 
-    for desired_tile in desired_tiles(desired_level, look_at, mvp) {
-        if !texture_cache.contains_key(&desired_tile.to_origin()) {
+    // The level of the cached tile drawn for each desired tile, if any.
+    let mut drawn_levels = HashMap::new();
+
+    for desired_tile in desired_tiles {
+        if !texture_cache.lookup(desired_tile) {
             tiles_to_fetch.push(desired_tile.clone());
         }
 
-        if let Some(tile_and_indices) = get_covering_tile(texture_cache, desired_tile) {
-            tiles_to_render.push(tile_and_indices);
+        let tile_to_render = get_covering_tile(texture_cache, desired_tile.clone());
+        drawn_levels.insert(
+            desired_tile.clone(),
+            tile_to_render.as_ref().map(|tile_to_render| tile_to_render.tile.level),
+        );
+
+        if let Some(tile_to_render) = tile_to_render {
+            tiles_to_render.push((desired_tile.clone(), tile_to_render));
         }
     }
 
-    (desired_level, tiles_to_render, tiles_to_fetch)
+    let tiles_to_render = tiles_to_render
+        .into_iter()
+        .map(|(desired_tile, mut tile_to_render)| {
+            tile_to_render.neighbor_levels =
+                neighbor_levels(&desired_tile, &drawn_levels, level_of_detail);
+            tile_to_render
+        })
+        .collect();
+
+    (level_of_detail, tiles_to_render, tiles_to_fetch)
 }
 
-fn desired_tiles(desired_level: u8, look_at: Vector2<f32>, mvp: Matrix4<f32>) -> Vec<Tile> {
+/// Finds the tiles to draw for a camera position.
+///
+/// Every visible tile is found by traversing the quadtree down from level zero, refining tiles
+/// until a cell of their elevation grid is no more than `max_screen_space_error` pixels wide on
+/// screen, so a single view can mix tiles from different levels. Tiles are never refined past
+/// `max_level`. Tiles outside the view frustum are skipped, along with all of their descendants.
+pub fn desired_tiles(
+    max_level: u8,
+    max_screen_space_error: f32,
+    viewport_size: (f32, f32),
+    texture_cache: &TileCache,
+    elevation_scale: &ElevationScale,
+    mvp: Matrix4<f32>,
+) -> Vec<Tile> {
     let frustum = Frustum::from_matrix4(mvp).unwrap();
-    let center = Tile::enclosing_point(desired_level, look_at.into());
 
-    let mut result = Vec::new();
+    let mut candidates: Vec<_> = visible_offsets(&mvp, elevation_scale.max_z())
+        .flat_map(|offset| {
+            (0..Tile::tiles_across_width(0)).map(move |x| Tile {
+                offset,
+                level: 0,
+                x,
+                y: 0,
+            })
+        })
+        .collect();
 
-    // TODO this is coupled with how the camera controller works; a fully correct solution being
-    // rather complicated, instead just document this behavior? (and export as a constant?)
-    let num_tiles_around = 6;
+    let mut result = Vec::new();
 
-    for delta_x in -num_tiles_around..num_tiles_around {
-        for delta_y in -num_tiles_around..num_tiles_around {
-            let tile = center.offset_by(delta_x, delta_y);
+    while let Some(tile) = candidates.pop() {
+        let bounding_box = tile_bounding_box(&tile, texture_cache, elevation_scale);
+        if frustum.contains(&bounding_box) == Relation::Out {
+            continue;
+        }
 
-            if tile_in_frustum(&tile, &frustum) && !result.contains(&tile) {
-                result.push(tile);
+        // A tile that is partly behind the camera has no size on screen to refine it by, and
+        // refining it would only find more such tiles at every level, so it is drawn as it is.
+        match screen_space_error(&tile, bounding_box.max.z, &mvp, viewport_size) {
+            Some(error) if tile.level < max_level && error > max_screen_space_error => {
+                candidates.extend(tile.children());
             }
+            _ => result.push(tile),
         }
     }
 
     result
 }
 
-fn tile_in_frustum(tile: &Tile, frustum: &Frustum<f32>) -> bool {
-    let a = tile.top_left_position();
-    let b = tile.bottom_right_position();
-    let bounding_box = Aabb3::new([a[0], a[1], 0.0].into(), [b[0], b[1], Z_UPPER_BOUND].into());
+/// The offsets of the copies of the world in the infinite-scroll map that may be visible.
+///
+/// All terrain is between `z = 0` and `z = max_z`, so this is found from where the view frustum
+/// lies between those planes. At most `MAX_VISIBLE_OFFSETS` copies of the world are considered,
+/// centered around the near plane of the frustum.
+fn visible_offsets(mvp: &Matrix4<f32>, max_z: f32) -> Range<i16> {
+    let inverse = match mvp.invert() {
+        Some(inverse) => inverse,
+        None => return 0..0,
+    };
+
+    let corner = |x: f32, y: f32, z: f32| {
+        let position = inverse * Vector4::new(x, y, z, 1.0);
+        position.truncate() / position.w
+    };
+
+    let ndc_corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+    let near: Vec<_> = ndc_corners.iter().map(|&(x, y)| corner(x, y, -1.0)).collect();
+    let far: Vec<_> = ndc_corners.iter().map(|&(x, y)| corner(x, y, 1.0)).collect();
+
+    let mut edges = Vec::new();
+    for i in 0..4 {
+        edges.push((near[i], near[(i + 1) % 4]));
+        edges.push((far[i], far[(i + 1) % 4]));
+        edges.push((near[i], far[i]));
+    }
+
+    let mut xs = Vec::new();
+    for &(a, b) in &edges {
+        for point in &[a, b] {
+            if 0.0 <= point.z && point.z <= max_z {
+                xs.push(point.x);
+            }
+        }
+
+        for &plane_z in &[0.0, max_z] {
+            if (a.z - plane_z) * (b.z - plane_z) < 0.0 {
+                let t = (plane_z - a.z) / (b.z - a.z);
+                xs.push(a.x + t * (b.x - a.x));
+            }
+        }
+    }
+
+    let xs = xs.into_iter().filter(|x| x.is_finite());
+    let (min_x, max_x) = xs.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), x| {
+        (min.min(x), max.max(x))
+    });
+
+    if min_x > max_x {
+        return 0..0;
+    }
 
-    frustum.contains(&bounding_box) != Relation::Out
+    let near_center_x = near.iter().map(|corner| corner.x).sum::<f32>() / 4.0;
+    let center_offset = (near_center_x / 2.0).floor().max(i16::MIN as f32).min(i16::MAX as f32);
+    let center_offset = center_offset as i16;
+
+    // The range is half-open, so this is `MAX_VISIBLE_OFFSETS` copies at most.
+    let min_offset = cmp::max(
+        (min_x / 2.0).floor() as i16,
+        center_offset.saturating_sub(MAX_VISIBLE_OFFSETS / 2),
+    );
+    let end_offset = cmp::min(
+        ((max_x / 2.0).floor() as i16).saturating_add(1),
+        center_offset.saturating_add(MAX_VISIBLE_OFFSETS / 2),
+    );
+
+    min_offset..end_offset
+}
+
+/// The bounding box of the terrain within `tile`.
+///
+/// The elevations used are those of the nearest ancestor of `tile` in cache (or `tile` itself),
+/// because that is the tile whose elevation data is drawn in place of `tile`. If there is no such
+/// tile, all possible elevations are included.
+fn tile_bounding_box(
+    tile: &Tile,
+    texture_cache: &TileCache,
+    elevation_scale: &ElevationScale,
+) -> Aabb3<f32> {
+    let (min_z, max_z) = match texture_cache.peek_nearest_ancestor(tile) {
+        Some((_, assets)) => (
+            elevation_scale.to_z(assets.metadata.min_elevation as f32),
+            elevation_scale.to_z(assets.metadata.max_elevation as f32),
+        ),
+        None => (0.0, elevation_scale.max_z()),
+    };
+
+    let a = tile.bottom_left_position();
+    let b = tile.top_right_position();
+    Aabb3::new([a[0], a[1], min_z].into(), [b[0], b[1], max_z].into())
+}
+
+/// An estimate of how many pixels wide a single cell of `tile`'s elevation grid appears on screen,
+/// if the tile were flat at height `z`, or `None` if the tile is partially behind the camera.
+fn screen_space_error(
+    tile: &Tile,
+    z: f32,
+    mvp: &Matrix4<f32>,
+    viewport_size: (f32, f32),
+) -> Option<f32> {
+    let corners = [
+        tile.bottom_left_position(),
+        tile.bottom_right_position(),
+        tile.top_right_position(),
+        tile.top_left_position(),
+    ];
+
+    let mut screen_corners = Vec::with_capacity(corners.len());
+    for corner in &corners {
+        let clip_position = mvp * Vector4::new(corner[0], corner[1], z, 1.0);
+        if clip_position.w <= 0.0 {
+            return None;
+        }
+
+        screen_corners.push(Vector2::new(
+            (viewport_size.0 / 2.0) * clip_position.x / clip_position.w,
+            (viewport_size.1 / 2.0) * clip_position.y / clip_position.w,
+        ));
+    }
+
+    let longest_edge = (0..screen_corners.len())
+        .map(|i| (screen_corners[(i + 1) % screen_corners.len()] - screen_corners[i]).magnitude())
+        .fold(0.0, f32::max);
+
+    Some(longest_edge / (ELEVATION_TILE_SIZE - 1) as f32)
+}
+
+/// Finds the levels of the cached tiles drawn next to each side of the desired tile `tile`.
+///
+/// `drawn_levels` has the level drawn for each desired tile, and `max_level` is the most detailed
+/// level among the desired tiles.
+fn neighbor_levels(
+    tile: &Tile,
+    drawn_levels: &HashMap<Tile, Option<u8>>,
+    max_level: u8,
+) -> NeighborLevels {
+    let mut result = [None; 4];
+
+    for (&side, levels) in SIDES.iter().zip(result.iter_mut()) {
+        let (x, y) = side.direction();
+        let neighbor = tile.offset_by(x, y);
+
+        // There is nothing beyond the poles.
+        if neighbor == *tile {
+            continue;
+        }
+
+        // The desired tile on this side is either the same size as `tile` or larger...
+        let larger_neighbor = iter::once(neighbor.clone())
+            .chain(neighbor.ancestors())
+            .filter_map(|candidate| drawn_levels.get(&candidate))
+            .next();
+
+        if let Some(&level) = larger_neighbor {
+            *levels = level.map(|level| (level, level));
+            continue;
+        }
+
+        // ...or there are several smaller desired tiles along this side.
+        let mut candidates = vec![neighbor];
+        while let Some(candidate) = candidates.pop() {
+            match drawn_levels.get(&candidate) {
+                Some(&Some(level)) => {
+                    *levels = Some(match *levels {
+                        Some((min, max)) => (cmp::min(min, level), cmp::max(max, level)),
+                        None => (level, level),
+                    });
+                }
+                Some(&None) => {}
+                None if candidate.level < max_level => {
+                    let facing_side = side.opposite();
+                    candidates.extend(
+                        candidate
+                            .children()
+                            .into_iter()
+                            .filter(|child| facing_side.is_along(child)),
+                    );
+                }
+                None => {}
+            }
+        }
+    }
+
+    result
 }
 
-fn get_covering_tile<R: gfx::Resources>(
-    cache: &mut LruCache<Tile, TileAssets<R>>,
-    tile_to_cover: Tile,
-) -> Option<(Tile, Vec<u32>)> {
+fn get_covering_tile(cache: &mut TileCache, tile_to_cover: Tile) -> Option<TileToRender> {
     find_parent_in_cache(tile_to_cover, cache).and_then(|(parent, quadrant_positions)| {
         let (mut width, mut left_x, mut top_y) = (ELEVATION_TILE_SIZE - 1, 0, 0);
 
         for position in quadrant_positions.iter().rev() {
+            // Past this depth, the tile to cover is smaller than a single cell of the parent's
+            // elevation grid. Render the whole cell that contains it.
+            if width == 1 {
+                break;
+            }
+
             width = width / 2;
 
             let (next_left_x, next_top_y) = match *position {
@@ -92,38 +383,27 @@ fn get_covering_tile<R: gfx::Resources>(
             top_y = next_top_y;
         }
 
-        let mut index_data = Vec::new();
-        for x in left_x..left_x + width {
-            for y in top_y..top_y + width {
-                let a = (x + 0) + (y + 0) * ELEVATION_TILE_SIZE;
-                let b = (x + 0) + (y + 1) * ELEVATION_TILE_SIZE;
-                let c = (x + 1) + (y + 0) * ELEVATION_TILE_SIZE;
-                let d = (x + 1) + (y + 1) * ELEVATION_TILE_SIZE;
-
-                index_data.extend_from_slice(&[a, b, c, c, b, d]);
-            }
-        }
-
-        Some((parent, index_data))
+        Some(TileToRender {
+            tile: parent,
+            left_x,
+            top_y,
+            width,
+            neighbor_levels: [None; 4],
+        })
     })
 }
 
-fn find_parent_in_cache<R: gfx::Resources>(
-    mut tile: Tile,
-    cache: &mut LruCache<Tile, TileAssets<R>>,
+fn find_parent_in_cache(
+    tile: Tile,
+    cache: &mut TileCache,
 ) -> Option<(Tile, Vec<PositionInParent>)> {
-    let mut quadrant_positions = vec![];
-
-    loop {
-        if cache.contains_key(&tile.to_origin()) {
-            return Some((tile, quadrant_positions));
-        }
+    cache.nearest_ancestor(&tile).map(|parent| {
+        let quadrant_positions = iter::once(tile.clone())
+            .chain(tile.ancestors())
+            .take_while(|candidate| candidate.level > parent.level)
+            .map(|candidate| candidate.position_in_parent().unwrap())
+            .collect();
 
-        if let Some(parent) = tile.parent() {
-            quadrant_positions.push(tile.position_in_parent().unwrap());
-            tile = parent;
-        } else {
-            return None;
-        }
-    }
+        (parent, quadrant_positions)
+    })
 }
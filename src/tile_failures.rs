@@ -0,0 +1,281 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use gaia_quadtree::Tile;
+
+use errors::*;
+use tile_cache::TileCache;
+
+/// How long to wait before trying to load a tile again after it first fails to load. Each failure
+/// after that doubles the wait, up to `MAX_RETRY_DELAY_SECS`.
+const INITIAL_RETRY_DELAY_SECS: u64 = 1;
+
+const MAX_RETRY_DELAY_SECS: u64 = 60;
+
+/// How long after a failed tile could have been retried it is forgotten, if it never was. Tiles
+/// that aren't retried in that time are usually no longer in view.
+const FORGET_AFTER_SECS: u64 = 5 * 60;
+
+/// The most failures to remember. Once there are this many, the failure that could have been
+/// retried the longest ago is forgotten to make room for a new one.
+const MAX_FAILURES: usize = 1024;
+
+/// Tiles that could not be loaded, so that they aren't requested again on every frame.
+///
+/// Failed tiles are retried with exponential backoff. Until then, their ancestors are fetched in
+/// their place. Failures are forgotten if they are not retried within `FORGET_AFTER_SECS`, so
+/// that panning over many missing tiles doesn't grow this without bound.
+///
+/// Methods take the current time as `now`.
+pub struct TileFailures {
+    failures: HashMap<Tile, Failure>,
+}
+
+struct Failure {
+    error: Error,
+    attempts: u32,
+    retry_at: Instant,
+}
+
+impl TileFailures {
+    pub fn new() -> TileFailures {
+        TileFailures {
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Record that `tile` failed to load because of `error`.
+    pub fn insert(&mut self, tile: &Tile, error: Error, now: Instant) {
+        self.failures.retain(|_, failure| !failure.is_stale(now));
+
+        let attempts = self.failures
+            .get(&tile.to_origin())
+            .map(|failure| failure.attempts + 1)
+            .unwrap_or(1);
+
+        let delay_secs = INITIAL_RETRY_DELAY_SECS
+            .checked_shl(attempts - 1)
+            .unwrap_or(MAX_RETRY_DELAY_SECS);
+        let delay_secs = cmp::min(delay_secs, MAX_RETRY_DELAY_SECS);
+
+        let failure = Failure {
+            error,
+            attempts,
+            retry_at: now + Duration::from_secs(delay_secs),
+        };
+
+        if self.failures.len() >= MAX_FAILURES && !self.failures.contains_key(&tile.to_origin()) {
+            let oldest = self.failures
+                .iter()
+                .min_by_key(|&(_, failure)| failure.retry_at)
+                .map(|(tile, _)| tile.clone());
+
+            if let Some(oldest) = oldest {
+                self.failures.remove(&oldest);
+            }
+        }
+
+        self.failures.insert(tile.to_origin(), failure);
+    }
+
+    /// Record that `tile` loaded successfully.
+    pub fn remove(&mut self, tile: &Tile) {
+        self.failures.remove(&tile.to_origin());
+    }
+
+    /// Whether `tile` has failed to load, and it is too soon to try again.
+    pub fn is_waiting(&self, tile: &Tile, now: Instant) -> bool {
+        self.failures
+            .get(&tile.to_origin())
+            .map(|failure| now < failure.retry_at)
+            .unwrap_or(false)
+    }
+
+    /// Replace each tile that is waiting to be retried with its nearest ancestor that can be
+    /// fetched instead. If an ancestor in `texture_cache` is found first, the tile is dropped,
+    /// because it already has something to fall back to.
+    pub fn fallbacks(
+        &self,
+        tiles: Vec<Tile>,
+        texture_cache: &TileCache,
+        now: Instant,
+    ) -> Vec<Tile> {
+        tiles
+            .into_iter()
+            .filter_map(|tile| {
+                if !self.is_waiting(&tile, now) {
+                    return Some(tile);
+                }
+
+                tile.ancestors()
+                    .take_while(|ancestor| !texture_cache.contains(ancestor))
+                    .find(|ancestor| !self.is_waiting(ancestor, now))
+            })
+            .collect()
+    }
+
+    /// Every tile that has failed to load and has not loaded or been forgotten since, with the
+    /// most recent error for each.
+    pub fn errors(&self, now: Instant) -> Vec<(&Tile, &Error)> {
+        self.failures
+            .iter()
+            .filter(|&(_, failure)| !failure.is_stale(now))
+            .map(|(tile, failure)| (tile, &failure.error))
+            .collect()
+    }
+}
+
+impl Failure {
+    /// Whether the tile could have been retried for longer than `FORGET_AFTER_SECS`.
+    fn is_stale(&self, now: Instant) -> bool {
+        now > self.retry_at + Duration::from_secs(FORGET_AFTER_SECS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x: u32) -> Tile {
+        Tile::new_at_origin(10, x, 0)
+    }
+
+    fn fail(failures: &mut TileFailures, tile: &Tile, now: Instant) {
+        failures.insert(tile, "Error loading tile".into(), now);
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// How long `tile` will wait before being retried, to the second.
+    fn retry_delay_secs(failures: &TileFailures, tile: &Tile, now: Instant) -> u64 {
+        (0..)
+            .find(|&delay_secs| !failures.is_waiting(tile, now + secs(delay_secs)))
+            .unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let mut failures = TileFailures::new();
+        let now = Instant::now();
+
+        for &expected_delay_secs in &[1, 2, 4, 8, 16, 32, 60, 60] {
+            fail(&mut failures, &tile(0), now);
+            assert_eq!(expected_delay_secs, retry_delay_secs(&failures, &tile(0), now));
+        }
+
+        assert!(!failures.is_waiting(&tile(1), now));
+    }
+
+    #[test]
+    fn backoff_saturates() {
+        let mut failures = TileFailures::new();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            fail(&mut failures, &tile(0), now);
+        }
+
+        assert_eq!(100, failures.failures[&tile(0)].attempts);
+        assert_eq!(MAX_RETRY_DELAY_SECS, retry_delay_secs(&failures, &tile(0), now));
+    }
+
+    #[test]
+    fn remove_resets_backoff() {
+        let mut failures = TileFailures::new();
+        let now = Instant::now();
+
+        fail(&mut failures, &tile(0), now);
+        fail(&mut failures, &tile(0), now);
+        failures.remove(&tile(0));
+
+        assert!(!failures.is_waiting(&tile(0), now));
+        assert!(failures.errors(now).is_empty());
+
+        fail(&mut failures, &tile(0), now);
+        assert_eq!(1, retry_delay_secs(&failures, &tile(0), now));
+    }
+
+    #[test]
+    fn offset_is_ignored() {
+        let mut failures = TileFailures::new();
+        let now = Instant::now();
+
+        let offset_tile = Tile {
+            offset: 2,
+            ..tile(0)
+        };
+        fail(&mut failures, &offset_tile, now);
+
+        assert!(failures.is_waiting(&tile(0), now));
+    }
+
+    #[test]
+    fn forgets_stale_failures() {
+        let mut failures = TileFailures::new();
+        let now = Instant::now();
+
+        fail(&mut failures, &tile(0), now);
+        assert_eq!(1, failures.errors(now).len());
+
+        let forgotten_at = now + secs(INITIAL_RETRY_DELAY_SECS + FORGET_AFTER_SECS + 1);
+        assert!(failures.errors(forgotten_at).is_empty());
+
+        fail(&mut failures, &tile(1), forgotten_at);
+        assert_eq!(1, failures.failures.len());
+        assert!(failures.failures.contains_key(&tile(1)));
+
+        // A forgotten tile starts its backoff over.
+        fail(&mut failures, &tile(0), forgotten_at);
+        assert_eq!(1, retry_delay_secs(&failures, &tile(0), forgotten_at));
+    }
+
+    #[test]
+    fn evicts_oldest_failure() {
+        let mut failures = TileFailures::new();
+        let now = Instant::now();
+
+        for x in 0..MAX_FAILURES as u32 {
+            fail(&mut failures, &tile(x), now + Duration::from_millis(x as u64));
+        }
+        assert_eq!(MAX_FAILURES, failures.failures.len());
+
+        let later = now + Duration::from_millis(MAX_FAILURES as u64);
+
+        // Failing again doesn't evict anything.
+        fail(&mut failures, &tile(5), later);
+        assert_eq!(MAX_FAILURES, failures.failures.len());
+
+        fail(&mut failures, &tile(MAX_FAILURES as u32), later);
+        assert_eq!(MAX_FAILURES, failures.failures.len());
+        assert!(!failures.failures.contains_key(&tile(0)));
+        assert!(failures.failures.contains_key(&tile(1)));
+        assert!(failures.failures.contains_key(&tile(MAX_FAILURES as u32)));
+    }
+
+    #[test]
+    fn fallbacks() {
+        let mut failures = TileFailures::new();
+        let cache = TileCache::new(0);
+        let now = Instant::now();
+
+        let parent = tile(0).parent().unwrap();
+        fail(&mut failures, &tile(0), now);
+
+        assert_eq!(
+            vec![parent.clone(), tile(2)],
+            failures.fallbacks(vec![tile(0), tile(2)], &cache, now)
+        );
+
+        fail(&mut failures, &parent, now);
+        assert_eq!(
+            vec![parent.parent().unwrap()],
+            failures.fallbacks(vec![tile(0)], &cache, now)
+        );
+
+        let retry_at = now + secs(INITIAL_RETRY_DELAY_SECS);
+        assert_eq!(vec![tile(0)], failures.fallbacks(vec![tile(0)], &cache, retry_at));
+    }
+}
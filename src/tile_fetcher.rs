@@ -1,30 +1,484 @@
-use std::sync::mpsc;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
 
+use cgmath::{InnerSpace, Vector2};
 use gaia_quadtree::Tile;
 
 use errors::*;
 use tile_asset_getter::TileAssetData;
+use tile_source::TileSource;
 
-pub fn fetch_tiles(
-    receive_tiles: mpsc::Receiver<Tile>,
-    send_textures: mpsc::Sender<(Tile, Result<TileAssetData>)>,
-) {
-    let mut jobs = Vec::new();
+/// How many tiles are loaded at once, each on its own thread.
+const NUM_WORKERS: usize = 4;
+
+/// The maximum number of tiles waiting to be loaded. When more are requested, the least important
+/// ones are dropped.
+const MAX_WAITING_TILES: usize = 64;
+
+/// Loads tiles from a `TileSource` on a pool of background threads.
+///
+/// Each frame, the tiles that are wanted are passed to `fetch`, which replaces the tiles that were
+/// waiting to be loaded. Tiles that are no longer wanted are therefore never loaded. Loaded tiles
+/// are collected with `receive`.
+///
+/// The threads run until `stop` is called.
+pub struct TileFetcher {
+    queue: Arc<Queue>,
+    receive_assets: mpsc::Receiver<(Tile, Result<TileAssetData>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+struct Queue {
+    jobs: Mutex<Jobs>,
+    job_added: Condvar,
+}
+
+struct Jobs {
+    /// Tiles waiting to be loaded, least important first.
+    waiting: Vec<Tile>,
+    /// Tiles being loaded by a worker, or that have been loaded but not yet received. Keeping
+    /// tiles here until they are received means they aren't fetched again in the meantime.
+    in_progress: HashSet<Tile>,
+    /// Whether workers should exit instead of loading more tiles.
+    stopped: bool,
+    /// Whether to load tiles' imagery. It isn't loaded when it wouldn't be drawn.
+    load_color: bool,
+}
+
+impl TileFetcher {
+    pub fn new(source: Arc<TileSource>, load_color: bool) -> Result<TileFetcher> {
+        let (send_assets, receive_assets) = mpsc::channel();
+
+        let queue = Arc::new(Queue {
+            jobs: Mutex::new(Jobs {
+                waiting: Vec::new(),
+                in_progress: HashSet::new(),
+                stopped: false,
+                load_color,
+            }),
+            job_added: Condvar::new(),
+        });
+
+        let mut fetcher = TileFetcher {
+            queue,
+            receive_assets,
+            workers: Vec::with_capacity(NUM_WORKERS),
+        };
+
+        for i in 0..NUM_WORKERS {
+            let queue = fetcher.queue.clone();
+            let source = source.clone();
+            let send_assets = send_assets.clone();
+
+            let worker = thread::Builder::new()
+                .name(format!("tile_fetcher_{}", i))
+                .spawn(move || work(&queue, &*source, &send_assets));
+
+            match worker {
+                Ok(worker) => fetcher.workers.push(worker),
+                Err(error) => {
+                    fetcher.stop();
+                    return Err(error).chain_err(|| "Error creating tile fetcher thread");
+                }
+            }
+        }
 
+        Ok(fetcher)
+    }
+
+    /// Set the tiles to load, cancelling any other tiles that were waiting to be loaded.
+    ///
+    /// `tiles` are loaded before `tiles_to_prefetch`. Within each of those, tiles at coarser levels
+    /// are loaded first, and then tiles closer to `view_center`. Tiles already being loaded are
+    /// not loaded again.
+    pub fn fetch(
+        &self,
+        tiles: &[Tile],
+        tiles_to_prefetch: &[Tile],
+        view_center: Option<Vector2<f32>>,
+    ) -> Result<()> {
+        let mut jobs = self.queue.jobs.lock().map_err(|_| "Tile fetcher queue was poisoned")?;
+
+        let mut waiting = prioritize(tiles, tiles_to_prefetch, &jobs.in_progress, view_center);
+        waiting.reverse();
+
+        jobs.waiting = waiting;
+        self.queue.job_added.notify_all();
+
+        Ok(())
+    }
+
+    /// Get the tiles that have been loaded since the last call, or the errors that stopped them
+    /// from loading.
+    pub fn receive(&self) -> Vec<(Tile, Result<TileAssetData>)> {
+        // Nothing panics while holding the lock, but if something did, `in_progress` is still
+        // usable.
+        let mut jobs = self.queue
+            .jobs
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+
+        let received: Vec<_> = self.receive_assets.try_iter().collect();
+        for result in &received {
+            jobs.in_progress.remove(&result.0);
+        }
+
+        received
+    }
+
+    /// Set whether to load tiles' imagery. Tiles already being loaded are not affected.
+    pub fn set_load_color(&self, load_color: bool) -> Result<()> {
+        let mut jobs = self.queue.jobs.lock().map_err(|_| "Tile fetcher queue was poisoned")?;
+        jobs.load_color = load_color;
+
+        Ok(())
+    }
+
+    /// How many tiles are waiting to be loaded, or are being loaded.
+    pub fn pending(&self) -> usize {
+        self.queue
+            .jobs
+            .lock()
+            .map(|jobs| jobs.waiting.len() + jobs.in_progress.len())
+            .unwrap_or(0)
+    }
+
+    /// Cancel every tile waiting to be loaded, and wait for the threads to finish the tiles they
+    /// are loading and exit.
+    pub fn stop(&mut self) {
+        {
+            // Workers only exit once they see `stopped`, so it must be set even if a worker
+            // panicked while holding the lock.
+            let mut jobs = self.queue
+                .jobs
+                .lock()
+                .unwrap_or_else(|error| error.into_inner());
+            jobs.waiting.clear();
+            jobs.stopped = true;
+        }
+
+        self.queue.job_added.notify_all();
+
+        for worker in self.workers.drain(..) {
+            // A worker that panicked has already stopped.
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The tiles to load, most important first, leaving out tiles in `in_progress`. See
+/// `TileFetcher::fetch`.
+fn prioritize(
+    tiles: &[Tile],
+    tiles_to_prefetch: &[Tile],
+    in_progress: &HashSet<Tile>,
+    view_center: Option<Vector2<f32>>,
+) -> Vec<Tile> {
+    let mut seen = HashSet::new();
+    let mut waiting: Vec<_> = tiles
+        .iter()
+        .map(|tile| (false, tile))
+        .chain(tiles_to_prefetch.iter().map(|tile| (true, tile)))
+        .filter(|&(_, tile)| !in_progress.contains(&tile.to_origin()))
+        .filter(|&(_, tile)| seen.insert(tile.to_origin()))
+        .map(|(is_prefetch, tile)| {
+            let distance = view_center
+                .map(|center| (tile_center(tile) - center).magnitude())
+                .unwrap_or(0.0);
+
+            ((is_prefetch, tile.level, distance), tile.to_origin())
+        })
+        .collect();
+
+    waiting.sort_by(|a, b| (a.0).partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+    waiting.truncate(MAX_WAITING_TILES);
+
+    waiting.into_iter().map(|(_, tile)| tile).collect()
+}
+
+/// Load tiles from `queue` until the fetcher is stopped, or there is nowhere left to send them.
+///
+/// Tiles stay in `in_progress` after they are sent, until `TileFetcher::receive` gets them.
+fn work(
+    queue: &Queue,
+    source: &TileSource,
+    send_assets: &mpsc::Sender<(Tile, Result<TileAssetData>)>,
+) {
     loop {
-        if jobs.is_empty() {
-            let tile = receive_tiles.recv().unwrap();
-            jobs.push(tile);
+        let (tile, load_color) = {
+            let mut jobs = match queue.jobs.lock() {
+                Ok(jobs) => jobs,
+                Err(_) => return,
+            };
+
+            while jobs.waiting.is_empty() && !jobs.stopped {
+                jobs = match queue.job_added.wait(jobs) {
+                    Ok(jobs) => jobs,
+                    Err(_) => return,
+                };
+            }
+
+            if jobs.stopped {
+                return;
+            }
+
+            let tile = jobs.waiting.pop().unwrap();
+            jobs.in_progress.insert(tile.clone());
+            (tile, jobs.load_color)
+        };
+
+        let assets = TileAssetData::new(source, &tile, load_color);
+
+        if send_assets.send((tile, assets)).is_err() {
+            return;
+        }
+    }
+}
+
+fn tile_center(tile: &Tile) -> Vector2<f32> {
+    let a = tile.bottom_left_position();
+    let b = tile.top_right_position();
+    Vector2::new((a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tile_source::MemoryTileSource;
+
+    use super::*;
+
+    /// Serves tiles from memory, but only once `open` is called, and counts how many times each
+    /// tile is loaded.
+    struct GatedSource {
+        tiles: MemoryTileSource,
+        open: Mutex<bool>,
+        opened: Condvar,
+        loads: Mutex<Vec<Tile>>,
+    }
+
+    impl GatedSource {
+        fn new(tiles: &[Tile]) -> GatedSource {
+            let mut memory_source = MemoryTileSource::new();
+            for tile in tiles {
+                let metadata = br#"{"min_elevation": 0, "max_elevation": 0, "polygons": [],
+                    "points": []}"#;
+                memory_source.insert(tile, Vec::new(), vec![0; 4], metadata.to_vec());
+            }
+
+            GatedSource {
+                tiles: memory_source,
+                open: Mutex::new(false),
+                opened: Condvar::new(),
+                loads: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+
+        fn loads(&self) -> Vec<Tile> {
+            self.loads.lock().unwrap().clone()
         }
+    }
+
+    impl TileSource for GatedSource {
+        fn color(&self, tile: &Tile) -> Result<Vec<u8>> {
+            self.tiles.color(tile)
+        }
+
+        fn elevation(&self, tile: &Tile) -> Result<Vec<u8>> {
+            self.loads.lock().unwrap().push(tile.clone());
 
-        for tile in receive_tiles.try_iter() {
-            if !jobs.contains(&tile) {
-                jobs.push(tile);
+            let mut open = self.open.lock().unwrap();
+            while !*open {
+                open = self.opened.wait(open).unwrap();
             }
+
+            self.tiles.elevation(tile)
+        }
+
+        fn metadata(&self, tile: &Tile) -> Result<Vec<u8>> {
+            self.tiles.metadata(tile)
+        }
+    }
+
+    fn tiles(level: u8, num_tiles: u32) -> Vec<Tile> {
+        (0..num_tiles).map(|x| Tile::new_at_origin(level, x, 0)).collect()
+    }
+
+    /// Wait for `condition` to hold, failing the test if it takes too long.
+    fn wait_for<F: FnMut() -> bool>(mut condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+            thread::sleep(Duration::from_millis(1));
         }
+    }
+
+    /// Receive from `fetcher` until `num_tiles` tiles have loaded.
+    fn receive(fetcher: &TileFetcher, num_tiles: usize) -> Vec<Tile> {
+        let mut received = Vec::new();
+        wait_for(|| {
+            for (tile, assets) in fetcher.receive() {
+                assert!(assets.is_ok());
+                received.push(tile);
+            }
+
+            received.len() >= num_tiles
+        });
+
+        received
+    }
+
+    #[test]
+    fn prioritizes_visible_coarse_and_near_tiles() {
+        let tile = |level, x, y| Tile::new_at_origin(level, x, y);
+        let center = tile_center(&tile(2, 5, 1));
+
+        let tiles = [tile(2, 0, 0), tile(2, 5, 1), tile(1, 0, 0), tile(2, 4, 1)];
+        let tiles_to_prefetch = [tile(0, 0, 0), tile(2, 5, 1), tile(3, 10, 2)];
+
+        assert_eq!(
+            vec![
+                tile(1, 0, 0),
+                tile(2, 5, 1),
+                tile(2, 4, 1),
+                tile(2, 0, 0),
+                tile(0, 0, 0),
+                tile(3, 10, 2),
+            ],
+            prioritize(&tiles, &tiles_to_prefetch, &HashSet::new(), Some(center))
+        );
+    }
+
+    #[test]
+    fn skips_duplicate_and_in_progress_tiles() {
+        let offset_tile = Tile {
+            offset: 2,
+            ..Tile::new_at_origin(1, 1, 0)
+        };
+        let tiles = [offset_tile, Tile::new_at_origin(1, 1, 0), Tile::new_at_origin(1, 2, 0)];
+
+        let mut in_progress = HashSet::new();
+        in_progress.insert(Tile::new_at_origin(1, 2, 0));
+
+        assert_eq!(
+            vec![Tile::new_at_origin(1, 1, 0)],
+            prioritize(&tiles, &[], &in_progress, None)
+        );
+    }
+
+    #[test]
+    fn caps_waiting_tiles() {
+        let tiles = tiles(8, MAX_WAITING_TILES as u32 + 10);
+
+        assert_eq!(
+            &tiles[..MAX_WAITING_TILES],
+            &prioritize(&[], &tiles, &HashSet::new(), None)[..]
+        );
+    }
+
+    #[test]
+    fn loads_tiles() {
+        let tiles = tiles(3, 6);
+        let source = Arc::new(GatedSource::new(&tiles));
+        source.open();
+
+        let mut fetcher = TileFetcher::new(source.clone(), false).unwrap();
+        fetcher.fetch(&tiles, &[], None).unwrap();
+
+        let mut received = receive(&fetcher, tiles.len());
+        received.sort_by_key(|tile| tile.x);
+
+        assert_eq!(tiles, received);
+        assert_eq!(0, fetcher.pending());
+
+        fetcher.stop();
+    }
+
+    #[test]
+    fn cancels_tiles_no_longer_wanted() {
+        let tiles = tiles(3, 8);
+        let source = Arc::new(GatedSource::new(&tiles));
+        let mut fetcher = TileFetcher::new(source.clone(), false).unwrap();
+
+        fetcher.fetch(&tiles[..6], &[], None).unwrap();
+        wait_for(|| source.loads().len() == NUM_WORKERS);
+        assert_eq!(6, fetcher.pending());
+
+        // The tiles already being loaded carry on, but the rest are replaced.
+        fetcher.fetch(&tiles[6..], &[], None).unwrap();
+        source.open();
+
+        let mut received = receive(&fetcher, NUM_WORKERS + 2);
+        received.sort_by_key(|tile| tile.x);
+
+        let mut expected = tiles[..NUM_WORKERS].to_vec();
+        expected.extend_from_slice(&tiles[6..]);
+        assert_eq!(expected, received);
+
+        fetcher.stop();
+        assert_eq!(NUM_WORKERS + 2, source.loads().len());
+    }
+
+    #[test]
+    fn does_not_fetch_unreceived_tiles_again() {
+        let tiles = tiles(3, 1);
+        let source = Arc::new(GatedSource::new(&tiles));
+        source.open();
+
+        let mut fetcher = TileFetcher::new(source.clone(), false).unwrap();
+        fetcher.fetch(&tiles, &[], None).unwrap();
+        wait_for(|| source.loads().len() == 1);
+
+        // The tile may have been sent, but it hasn't been received yet.
+        thread::sleep(Duration::from_millis(10));
+        fetcher.fetch(&tiles, &[], None).unwrap();
+
+        assert_eq!(tiles, receive(&fetcher, 1));
+        fetcher.stop();
+        assert_eq!(tiles, source.loads());
+    }
+
+    #[test]
+    fn stop_joins_idle_workers() {
+        let source = Arc::new(GatedSource::new(&[]));
+        let mut fetcher = TileFetcher::new(source.clone(), false).unwrap();
+        fetcher.stop();
+
+        // Each worker holds a reference to the source until it exits.
+        assert_eq!(1, Arc::strong_count(&source));
+
+        // Stopping again does nothing.
+        fetcher.stop();
+    }
+
+    #[test]
+    fn stop_waits_for_tiles_being_loaded() {
+        let tiles = tiles(3, 8);
+        let source = Arc::new(GatedSource::new(&tiles));
+        let mut fetcher = TileFetcher::new(source.clone(), false).unwrap();
+
+        fetcher.fetch(&tiles, &[], None).unwrap();
+        wait_for(|| source.loads().len() == NUM_WORKERS);
+
+        let gate = source.clone();
+        let opener = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            gate.open();
+        });
+
+        fetcher.stop();
+        opener.join().unwrap();
 
-        let tile = jobs.pop().unwrap();
-        let textures = TileAssetData::new(&tile);
-        send_textures.send((tile, textures)).unwrap();
+        // The tiles that were waiting are never loaded.
+        assert_eq!(NUM_WORKERS, source.loads().len());
+        assert_eq!(1, Arc::strong_count(&source));
     }
 }
@@ -0,0 +1,295 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+use std::time::Instant;
+
+use gaia_assetgen::texture;
+use gaia_assetgen::{ELEVATION_TILE_SIZE, IMAGERY_TILE_SIZE};
+use gfx;
+
+use errors::*;
+use tile_asset_getter::{TileAssetData, TileAssets};
+
+/// The most tiles a page of a `TilePool` holds. OpenGL 3 only guarantees texture arrays with 256
+/// layers, and smaller pages mean less memory is allocated that isn't used yet.
+const PAGE_CAPACITY: usize = 64;
+
+/// How much GPU memory the textures of a tile take up: its mipmapped RGBA color texture and its
+/// 16-bit elevation texture.
+pub fn tile_size_in_bytes() -> usize {
+    let color_size: usize = texture::mip_sizes(IMAGERY_TILE_SIZE)
+        .iter()
+        .map(|&size| 4 * size as usize * size as usize)
+        .sum();
+
+    color_size + 2 * ELEVATION_TILE_SIZE as usize * ELEVATION_TILE_SIZE as usize
+}
+
+/// Texture arrays that hold the color and elevation textures of every cached tile, one tile per
+/// layer, so that all of the terrain can be drawn with a few instanced draws rather than one draw
+/// per tile.
+///
+/// The arrays are split into pages, which are created as more tiles are uploaded, until there are
+/// enough for the budget. Pages are kept once they are created, unless the budget shrinks.
+pub struct TilePool<R: gfx::Resources> {
+    pages: Vec<TilePage<R>>,
+    budget_bytes: usize,
+}
+
+/// A page of a `TilePool`: a color and an elevation texture array, and which of their layers are
+/// free.
+pub struct TilePage<R: gfx::Resources> {
+    pub color: gfx::handle::ShaderResourceView<R, [f32; 4]>,
+    pub elevation: gfx::handle::ShaderResourceView<R, u32>,
+    color_texture: gfx::handle::Texture<R, gfx::format::R8_G8_B8_A8>,
+    elevation_texture: gfx::handle::Texture<R, gfx::format::R16>,
+    free_layers: Rc<RefCell<Vec<u16>>>,
+}
+
+/// A layer of a page of a `TilePool` that holds a tile. The layer is freed when the slot is
+/// dropped.
+pub struct TileSlot {
+    page: u16,
+    layer: u16,
+    free_layers: Rc<RefCell<Vec<u16>>>,
+}
+
+impl TileSlot {
+    /// The index of the slot's page in `TilePool::pages`.
+    pub fn page(&self) -> u16 {
+        self.page
+    }
+
+    pub fn layer(&self) -> u16 {
+        self.layer
+    }
+}
+
+#[cfg(test)]
+impl TileSlot {
+    /// A slot that isn't in any pool, for testing code that keeps track of tiles.
+    pub fn detached() -> TileSlot {
+        TileSlot {
+            page: 0,
+            layer: 0,
+            free_layers: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+}
+
+impl Drop for TileSlot {
+    fn drop(&mut self) {
+        self.free_layers.borrow_mut().push(self.layer);
+    }
+}
+
+impl<R: gfx::Resources> TilePool<R> {
+    pub fn new(budget_bytes: usize) -> TilePool<R> {
+        TilePool {
+            pages: Vec::new(),
+            budget_bytes,
+        }
+    }
+
+    /// Change the budget. If the pool has more pages than fit in the new budget, every page is
+    /// dropped, and every tile has to be uploaded again. Returns whether this happened.
+    pub fn set_budget(&mut self, budget_bytes: usize) -> bool {
+        self.budget_bytes = budget_bytes;
+
+        let shrunk = self.pages.len() > page_capacities(budget_bytes).len();
+        if shrunk {
+            self.pages.clear();
+        }
+
+        shrunk
+    }
+
+    /// The pages that have been created so far.
+    pub fn pages(&self) -> &[TilePage<R>] {
+        &self.pages
+    }
+
+    /// Whether another tile can be uploaded without freeing a slot first.
+    pub fn has_free_slot(&self) -> bool {
+        self.pages.len() < page_capacities(self.budget_bytes).len()
+            || self.pages
+                .iter()
+                .any(|page| !page.free_layers.borrow().is_empty())
+    }
+
+    /// Copy a tile's textures into a free layer of one of the pages, creating a page if they are
+    /// all full.
+    pub fn upload<C: gfx::CommandBuffer<R>, F: gfx::Factory<R>>(
+        &mut self,
+        factory: &mut F,
+        encoder: &mut gfx::Encoder<R, C>,
+        data: TileAssetData,
+    ) -> Result<TileAssets> {
+        let free_page = self.pages
+            .iter()
+            .position(|page| !page.free_layers.borrow().is_empty());
+
+        let page_index = match free_page {
+            Some(page_index) => page_index,
+            None => {
+                let capacities = page_capacities(self.budget_bytes);
+                let capacity = *capacities
+                    .get(self.pages.len())
+                    .ok_or("No free slots in tile pool")?;
+
+                self.pages.push(TilePage::new(factory, capacity)?);
+                self.pages.len() - 1
+            }
+        };
+
+        let page = &self.pages[page_index];
+        let layer = page.free_layers.borrow_mut().pop().unwrap();
+
+        let slot = TileSlot {
+            page: page_index as u16,
+            layer,
+            free_layers: page.free_layers.clone(),
+        };
+
+        // A tile without imagery leaves whatever was in its layer before, which isn't drawn.
+        let color = data.color.unwrap_or_default();
+
+        let mip_sizes = texture::mip_sizes(IMAGERY_TILE_SIZE);
+        for (level, (level_data, &level_size)) in color.iter().zip(&mip_sizes).enumerate() {
+            let image_info = gfx::texture::NewImageInfo {
+                xoffset: 0,
+                yoffset: 0,
+                zoffset: layer,
+                width: level_size as u16,
+                height: level_size as u16,
+                depth: 1,
+                format: (),
+                mipmap: level as gfx::texture::Level,
+            };
+
+            encoder
+                .update_texture::<_, gfx::format::Srgba8>(
+                    &page.color_texture,
+                    None,
+                    image_info,
+                    gfx::memory::cast_slice(level_data),
+                )
+                .map_err(|error| format!("Could not upload color texture: {:?}", error))?;
+        }
+
+        let image_info = gfx::texture::NewImageInfo {
+            xoffset: 0,
+            yoffset: 0,
+            zoffset: layer,
+            width: ELEVATION_TILE_SIZE as u16,
+            height: ELEVATION_TILE_SIZE as u16,
+            depth: 1,
+            format: (),
+            mipmap: 0,
+        };
+
+        encoder
+            .update_texture::<_, (gfx::format::R16, gfx::format::Uint)>(
+                &page.elevation_texture,
+                None,
+                image_info,
+                &data.elevation,
+            )
+            .map_err(|error| format!("Could not upload elevation texture: {:?}", error))?;
+
+        Ok(TileAssets {
+            slot,
+            metadata: data.metadata,
+            size_in_bytes: tile_size_in_bytes(),
+            uploaded_at: Instant::now(),
+        })
+    }
+}
+
+impl<R: gfx::Resources> TilePage<R> {
+    fn new<F: gfx::Factory<R>>(factory: &mut F, capacity: usize) -> Result<TilePage<R>> {
+        let num_layers = capacity as gfx::texture::Layer;
+
+        let num_mip_levels = texture::mip_sizes(IMAGERY_TILE_SIZE).len() as gfx::texture::Level;
+        let color_texture = create_array(
+            factory,
+            IMAGERY_TILE_SIZE,
+            num_layers,
+            num_mip_levels,
+            gfx::format::ChannelType::Srgb,
+        ).chain_err(|| "Could not create color texture array")?;
+        let color = factory
+            .view_texture_as_shader_resource::<gfx::format::Srgba8>(
+                &color_texture,
+                (0, num_mip_levels - 1),
+                gfx::format::Swizzle::new(),
+            )
+            .chain_err(|| "Could not view color texture array")?;
+
+        let elevation_texture = create_array(
+            factory,
+            ELEVATION_TILE_SIZE,
+            num_layers,
+            1,
+            gfx::format::ChannelType::Uint,
+        ).chain_err(|| "Could not create elevation texture array")?;
+        let elevation = factory
+            .view_texture_as_shader_resource::<(gfx::format::R16, gfx::format::Uint)>(
+                &elevation_texture,
+                (0, 0),
+                gfx::format::Swizzle::new(),
+            )
+            .chain_err(|| "Could not view elevation texture array")?;
+
+        // Layers are handed out from the end of the list, so this starts with the first layer.
+        let free_layers = (0..num_layers).rev().collect();
+
+        Ok(TilePage {
+            color,
+            elevation,
+            color_texture,
+            elevation_texture,
+            free_layers: Rc::new(RefCell::new(free_layers)),
+        })
+    }
+}
+
+/// How many tiles each page holds, for a given budget. Every page but the last is full-sized.
+fn page_capacities(budget_bytes: usize) -> Vec<usize> {
+    let mut remaining = cmp::max(budget_bytes / tile_size_in_bytes(), 1);
+    let mut capacities = Vec::new();
+
+    while remaining > 0 {
+        let capacity = cmp::min(remaining, PAGE_CAPACITY);
+        capacities.push(capacity);
+        remaining -= capacity;
+    }
+
+    capacities
+}
+
+/// Create a texture array that can be updated one layer at a time.
+fn create_array<R: gfx::Resources, F: gfx::Factory<R>, S: gfx::format::SurfaceTyped>(
+    factory: &mut F,
+    size: u32,
+    num_layers: gfx::texture::Layer,
+    num_mip_levels: gfx::texture::Level,
+    channel: gfx::format::ChannelType,
+) -> Result<gfx::handle::Texture<R, S>> {
+    let kind = gfx::texture::Kind::D2Array(
+        size as u16,
+        size as u16,
+        num_layers,
+        gfx::texture::AaMode::Single,
+    );
+
+    factory
+        .create_texture(
+            kind,
+            num_mip_levels,
+            gfx::memory::Bind::SHADER_RESOURCE | gfx::memory::Bind::TRANSFER_DST,
+            gfx::memory::Usage::Dynamic,
+            Some(channel),
+        )
+        .chain_err(|| "Could not create texture")
+}
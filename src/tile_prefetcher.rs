@@ -0,0 +1,151 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use cgmath::{Matrix4, SquareMatrix, Vector2, Vector4};
+use gaia_quadtree::Tile;
+
+use elevation::ElevationScale;
+use tile_cache::TileCache;
+use tile_chooser;
+
+/// How many past frames to estimate the camera's velocity from.
+const HISTORY_LENGTH: usize = 10;
+
+/// How far into the future to predict the camera's position.
+const LOOKAHEAD: f32 = 0.5;
+
+/// Chooses tiles to fetch before they are visible, so that they are already in cache once they
+/// are.
+///
+/// Two kinds of tiles are prefetched, most important first: the tiles that will be visible if the
+/// camera keeps panning the way it has been over the last few frames, and the tiles one level
+/// coarser and one level finer than those currently visible, for when the camera zooms.
+///
+/// Prefetched tiles are only ever fetched, never rendered.
+pub struct TilePrefetcher {
+    /// The maximum number of tiles to prefetch per frame.
+    budget: usize,
+    /// The most detailed level to prefetch tiles at.
+    max_level: u8,
+    /// When each recent frame was rendered, and the point at the center of the view during it,
+    /// oldest first.
+    history: VecDeque<(Instant, Vector2<f32>)>,
+}
+
+impl TilePrefetcher {
+    pub fn new(budget: usize, max_level: u8) -> TilePrefetcher {
+        TilePrefetcher {
+            budget,
+            max_level,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    /// Record the camera position for this frame, and get the tiles to prefetch.
+    ///
+    /// `desired_tiles` are the tiles to draw for this frame, as chosen by
+    /// `tile_chooser::desired_tiles` from the other arguments. These, and any tiles already in
+    /// cache, are never prefetched.
+    pub fn tiles_to_prefetch(
+        &mut self,
+        desired_tiles: &[Tile],
+        max_screen_space_error: f32,
+        viewport_size: (f32, f32),
+        texture_cache: &TileCache,
+        elevation_scale: &ElevationScale,
+        mvp: Matrix4<f32>,
+    ) -> Vec<Tile> {
+        if let Some(center) = view_center(&mvp) {
+            if self.history.len() == HISTORY_LENGTH {
+                self.history.pop_front();
+            }
+
+            self.history.push_back((Instant::now(), center));
+        }
+
+        if self.budget == 0 {
+            return Vec::new();
+        }
+
+        let mut candidates = Vec::new();
+
+        if let Some(movement) = self.predicted_movement() {
+            // Moving the world by the opposite of the camera's movement puts the predicted center
+            // of the view at the center of the screen.
+            let predicted_mvp = mvp * Matrix4::from_translation(-movement.extend(0.0));
+
+            candidates.extend(tile_chooser::desired_tiles(
+                self.max_level,
+                max_screen_space_error,
+                viewport_size,
+                texture_cache,
+                elevation_scale,
+                predicted_mvp,
+            ));
+        }
+
+        candidates.extend(desired_tiles.iter().filter_map(Tile::parent));
+        candidates.extend(
+            desired_tiles
+                .iter()
+                .flat_map(Tile::children)
+                .filter(|child| child.level <= self.max_level),
+        );
+
+        let desired_tiles: HashSet<_> = desired_tiles.iter().collect();
+        let mut seen = HashSet::new();
+
+        candidates
+            .into_iter()
+            .filter(|tile| !desired_tiles.contains(&tile) && !texture_cache.contains(tile))
+            .filter(|tile| seen.insert(tile.clone()))
+            .take(self.budget)
+            .collect()
+    }
+
+    /// How far the center of the view will have moved in `LOOKAHEAD` seconds, if the camera
+    /// keeps moving at the same speed as over the frames in `history`.
+    fn predicted_movement(&self) -> Option<Vector2<f32>> {
+        let &(first_time, first_center) = self.history.front()?;
+        let &(last_time, last_center) = self.history.back()?;
+
+        let elapsed = seconds(last_time.duration_since(first_time));
+        if elapsed == 0.0 || first_center == last_center {
+            return None;
+        }
+
+        Some((last_center - first_center) * (LOOKAHEAD / elapsed))
+    }
+}
+
+/// The point on the `z = 0` plane at the center of the screen, if the camera is looking at that
+/// plane.
+pub fn view_center(mvp: &Matrix4<f32>) -> Option<Vector2<f32>> {
+    let inverse = mvp.invert()?;
+
+    let unproject = |z: f32| {
+        let position = inverse * Vector4::new(0.0, 0.0, z, 1.0);
+        position.truncate() / position.w
+    };
+
+    let (near, far) = (unproject(-1.0), unproject(1.0));
+    if near.z == far.z {
+        return None;
+    }
+
+    let t = near.z / (near.z - far.z);
+    if t < 0.0 {
+        return None;
+    }
+
+    let center = near + (far - near) * t;
+    Some(Vector2::new(center.x, center.y))
+}
+
+fn seconds(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
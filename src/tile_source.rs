@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use gaia_assetgen::archive::{ArchiveReader, AssetKind};
+use gaia_assetgen::MAX_LEVEL;
+use gaia_quadtree::Tile;
+
+use errors::*;
+
+/// Where the assets for each tile are loaded from.
+///
+/// Assets are returned in the formats that `gaia_assetgen` generates them in:
+///
+/// * Imagery is an image, in any format that the `image` crate can read. Gaia generates JPEGs.
+///   It isn't loaded when none of it is drawn, so a source can do without imagery if terrain is
+///   only colored by elevation. See `TerrainColoring::shows_imagery`.
+/// * Elevation is a grid of little-endian `u16`s, row by row from the top.
+/// * Metadata is a JSON-encoded `gaia_assetgen::TileMetadata`.
+/// * Compressed imagery, which is optional, is in the format described in
+///   `gaia_assetgen::texture`. When present, it is used instead of the imagery.
+///
+/// The offset of tiles passed to a source should be ignored. Sources are called from background
+/// threads.
+pub trait TileSource: Send + Sync {
+    fn color(&self, tile: &Tile) -> Result<Vec<u8>>;
+    fn elevation(&self, tile: &Tile) -> Result<Vec<u8>>;
+    fn metadata(&self, tile: &Tile) -> Result<Vec<u8>>;
+
+    /// The most detailed level that the source has tiles at. Tiles at deeper levels are never
+    /// asked for. By default, this is the deepest level that `gaia_assetgen` generates.
+    fn max_level(&self) -> u8 {
+        MAX_LEVEL
+    }
+
+    /// The tile's compressed imagery, or `None` if the source doesn't have any.
+    fn compressed_color(&self, _tile: &Tile) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// Loads tiles from a directory of files, as written by `gaia_assetgen`.
+///
+/// Each tile has three files in the directory, named `{level}_{x}_{y}.jpg`,
+/// `{level}_{x}_{y}.gray` and `{level}_{x}_{y}.json`, and optionally a fourth with compressed
+/// imagery, named `{level}_{x}_{y}.bc`.
+#[derive(Debug, Clone)]
+pub struct FileSystemTileSource {
+    root: PathBuf,
+}
+
+impl FileSystemTileSource {
+    pub fn new<P: AsRef<Path>>(root: P) -> FileSystemTileSource {
+        FileSystemTileSource {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, tile: &Tile, extension: &str) -> PathBuf {
+        let file_name = format!("{}_{}_{}.{}", tile.level, tile.x, tile.y, extension);
+        self.root.join(file_name)
+    }
+
+    fn read(&self, tile: &Tile, extension: &str) -> Result<Vec<u8>> {
+        let path = self.path(tile, extension);
+
+        let mut buf = Vec::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut buf))
+            .chain_err(|| format!("Error reading {}", path.display()))?;
+
+        Ok(buf)
+    }
+}
+
+impl Default for FileSystemTileSource {
+    /// Loads tiles from `assets/generated/tiles`, relative to the current directory.
+    fn default() -> FileSystemTileSource {
+        FileSystemTileSource::new("assets/generated/tiles")
+    }
+}
+
+impl TileSource for FileSystemTileSource {
+    fn color(&self, tile: &Tile) -> Result<Vec<u8>> {
+        self.read(tile, "jpg")
+    }
+
+    fn elevation(&self, tile: &Tile) -> Result<Vec<u8>> {
+        self.read(tile, "gray")
+    }
+
+    fn metadata(&self, tile: &Tile) -> Result<Vec<u8>> {
+        self.read(tile, "json")
+    }
+
+    fn compressed_color(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        if self.path(tile, "bc").exists() {
+            self.read(tile, "bc").map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Serves tiles that are held in memory.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTileSource {
+    tiles: HashMap<Tile, MemoryTile>,
+}
+
+#[derive(Debug, Clone)]
+struct MemoryTile {
+    color: Vec<u8>,
+    elevation: Vec<u8>,
+    metadata: Vec<u8>,
+}
+
+impl MemoryTileSource {
+    pub fn new() -> MemoryTileSource {
+        MemoryTileSource::default()
+    }
+
+    /// Add or replace the assets for `tile`. See `TileSource` for their formats.
+    pub fn insert(&mut self, tile: &Tile, color: Vec<u8>, elevation: Vec<u8>, metadata: Vec<u8>) {
+        let memory_tile = MemoryTile {
+            color,
+            elevation,
+            metadata,
+        };
+
+        self.tiles.insert(tile.to_origin(), memory_tile);
+    }
+
+    fn get(&self, tile: &Tile) -> Result<&MemoryTile> {
+        self.tiles
+            .get(&tile.to_origin())
+            .ok_or_else(|| format!("No tile {} in memory", tile).into())
+    }
+}
+
+impl TileSource for MemoryTileSource {
+    fn color(&self, tile: &Tile) -> Result<Vec<u8>> {
+        Ok(self.get(tile)?.color.clone())
+    }
+
+    fn elevation(&self, tile: &Tile) -> Result<Vec<u8>> {
+        Ok(self.get(tile)?.elevation.clone())
+    }
+
+    fn metadata(&self, tile: &Tile) -> Result<Vec<u8>> {
+        Ok(self.get(tile)?.metadata.clone())
+    }
+
+    /// The level of the most detailed tile in memory.
+    fn max_level(&self) -> u8 {
+        self.tiles.keys().map(|tile| tile.level).max().unwrap_or(0)
+    }
+}
+
+/// Loads tiles from a single archive file, as written by `gaia_assetgen` when given an archive
+/// file. Only the assets that are asked for are read from the file.
+pub struct ArchiveTileSource {
+    path: PathBuf,
+    /// Readers of the archive that aren't in use. Each read takes a reader, opening another if
+    /// they are all in use, so that threads reading at the same time don't wait for each other.
+    readers: Mutex<Vec<ArchiveReader<BufReader<File>>>>,
+}
+
+impl ArchiveTileSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ArchiveTileSource> {
+        let path = path.as_ref().to_path_buf();
+        let reader = open_archive(&path)?;
+
+        Ok(ArchiveTileSource {
+            path,
+            readers: Mutex::new(vec![reader]),
+        })
+    }
+
+    fn read(&self, tile: &Tile, kind: AssetKind) -> Result<Vec<u8>> {
+        self.read_optional(tile, kind)?
+            .ok_or_else(|| format!("No {} for tile {} in archive", kind.extension(), tile).into())
+    }
+
+    fn read_optional(&self, tile: &Tile, kind: AssetKind) -> Result<Option<Vec<u8>>> {
+        let reader = self.readers
+            .lock()
+            .map_err(|_| "Tile archive readers were poisoned")?
+            .pop();
+
+        let mut reader = match reader {
+            Some(reader) => reader,
+            None => open_archive(&self.path)?,
+        };
+
+        let contents = reader
+            .read(tile, kind)
+            .chain_err(|| "Error reading from tile archive");
+
+        if let Ok(mut readers) = self.readers.lock() {
+            readers.push(reader);
+        }
+
+        contents
+    }
+}
+
+fn open_archive(path: &Path) -> Result<ArchiveReader<BufReader<File>>> {
+    let file = File::open(path).chain_err(|| format!("Error opening {}", path.display()))?;
+
+    ArchiveReader::new(BufReader::new(file))
+        .chain_err(|| format!("Error reading tile archive {}", path.display()))
+}
+
+impl TileSource for ArchiveTileSource {
+    fn color(&self, tile: &Tile) -> Result<Vec<u8>> {
+        self.read(tile, AssetKind::Color)
+    }
+
+    fn elevation(&self, tile: &Tile) -> Result<Vec<u8>> {
+        self.read(tile, AssetKind::Elevation)
+    }
+
+    fn metadata(&self, tile: &Tile) -> Result<Vec<u8>> {
+        self.read(tile, AssetKind::Metadata)
+    }
+
+    fn compressed_color(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        self.read_optional(tile, AssetKind::CompressedColor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use gaia_assetgen::archive::ArchiveWriter;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn write_file(path: &Path, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn tile() -> Tile {
+        Tile::new_at_origin(2, 3, 1)
+    }
+
+    fn write_archive(path: &Path) {
+        let mut writer = ArchiveWriter::new(File::create(path).unwrap()).unwrap();
+        writer.add(&tile(), AssetKind::Color, b"color").unwrap();
+        writer.add(&tile(), AssetKind::Elevation, b"elevation").unwrap();
+        writer.add(&tile(), AssetKind::Metadata, b"metadata").unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn assert_hit(source: &TileSource, tile: &Tile) {
+        assert_eq!(b"color".to_vec(), source.color(tile).unwrap());
+        assert_eq!(b"elevation".to_vec(), source.elevation(tile).unwrap());
+        assert_eq!(b"metadata".to_vec(), source.metadata(tile).unwrap());
+        assert_eq!(None, source.compressed_color(tile).unwrap());
+    }
+
+    fn assert_miss(source: &TileSource, tile: &Tile) {
+        assert!(source.color(tile).is_err());
+        assert!(source.elevation(tile).is_err());
+        assert!(source.metadata(tile).is_err());
+    }
+
+    #[test]
+    fn memory_source() {
+        let mut source = MemoryTileSource::new();
+        assert_eq!(0, source.max_level());
+
+        source.insert(&tile(), b"color".to_vec(), b"elevation".to_vec(), b"metadata".to_vec());
+
+        let offset_tile = Tile {
+            offset: 3,
+            ..tile()
+        };
+
+        assert_hit(&source, &tile());
+        assert_hit(&source, &offset_tile);
+        assert_miss(&source, &Tile::new_at_origin(2, 2, 1));
+        assert_eq!(2, source.max_level());
+    }
+
+    #[test]
+    fn file_system_source() {
+        let dir = TempDir::new("gaia_tiles").unwrap();
+        write_file(&dir.path().join("2_3_1.jpg"), b"color");
+        write_file(&dir.path().join("2_3_1.gray"), b"elevation");
+        write_file(&dir.path().join("2_3_1.json"), b"metadata");
+
+        let source = FileSystemTileSource::new(dir.path());
+        assert_hit(&source, &tile());
+        assert_miss(&source, &Tile::new_at_origin(2, 2, 1));
+
+        write_file(&dir.path().join("2_3_1.bc"), b"compressed");
+        assert_eq!(Some(b"compressed".to_vec()), source.compressed_color(&tile()).unwrap());
+    }
+
+    #[test]
+    fn archive_source() {
+        let dir = TempDir::new("gaia_tiles").unwrap();
+        let path = dir.path().join("tiles.gaia");
+        write_archive(&path);
+
+        let source = ArchiveTileSource::open(&path).unwrap();
+        assert_hit(&source, &tile());
+        assert_miss(&source, &Tile::new_at_origin(2, 2, 1));
+    }
+
+    #[test]
+    fn archive_source_reads_concurrently() {
+        let dir = TempDir::new("gaia_tiles").unwrap();
+        let path = dir.path().join("tiles.gaia");
+        write_archive(&path);
+
+        let source = ArchiveTileSource::open(&path).unwrap();
+
+        // Hold on to a reader, as if another thread were reading with it.
+        let busy_reader = source.readers.lock().unwrap().pop().unwrap();
+        assert_hit(&source, &tile());
+        source.readers.lock().unwrap().push(busy_reader);
+
+        assert_eq!(2, source.readers.lock().unwrap().len());
+    }
+
+    #[test]
+    fn malformed_archive() {
+        let dir = TempDir::new("gaia_tiles").unwrap();
+        let path = dir.path().join("tiles.gaia");
+        write_file(&path, b"not an archive");
+
+        assert!(ArchiveTileSource::open(&path).is_err());
+        assert!(ArchiveTileSource::open(dir.path().join("missing.gaia")).is_err());
+    }
+}